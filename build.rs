@@ -0,0 +1,50 @@
+//! Records what is being built, and how, for `rust2prod_api::build_info`.
+use std::env;
+use std::process::Command;
+
+fn main() {
+    // Docker builds can pass the commit in, as `.git` may be missing there
+    println!("cargo:rerun-if-env-changed=GIT_COMMIT");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+    println!("cargo:rerun-if-changed=build.rs");
+
+    let git_commit = env::var("GIT_COMMIT")
+        .ok()
+        .filter(|commit| !commit.is_empty())
+        .or_else(|| command_output("git", &["rev-parse", "HEAD"]))
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=GIT_COMMIT={}", git_commit);
+
+    // Reproducible builds pin the timestamp through `SOURCE_DATE_EPOCH`
+    let built_at = env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.parse::<i64>().ok())
+        .and_then(|epoch| chrono::NaiveDateTime::from_timestamp_opt(epoch, 0))
+        .map(|built_at| chrono::DateTime::<chrono::Utc>::from_utc(built_at, chrono::Utc))
+        .unwrap_or_else(chrono::Utc::now);
+    println!(
+        "cargo:rustc-env=BUILD_TIMESTAMP={}",
+        built_at.to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+    );
+
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let rustc_version = command_output(&rustc, &["--version"]).unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=RUSTC_VERSION={}", rustc_version);
+
+    let mut features: Vec<String> = env::vars()
+        .filter_map(|(name, _)| name.strip_prefix("CARGO_FEATURE_").map(|feature| feature.to_lowercase().replace('_', "-")))
+        .collect();
+    features.sort();
+    println!("cargo:rustc-env=CARGO_FEATURES={}", features.join(","));
+}
+
+fn command_output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let output = String::from_utf8(output.stdout).ok()?;
+    Some(output.trim().to_string()).filter(|output| !output.is_empty())
+}
@@ -0,0 +1,14 @@
+-- Create Newsletter Issues Table
+CREATE TABLE newsletter_issues(
+   id uuid NOT NULL,
+   PRIMARY KEY (id),
+   title TEXT NOT NULL,
+   markdown TEXT NOT NULL,
+   state TEXT NOT NULL CHECK (state IN ('draft', 'scheduled', 'sending', 'sent', 'cancelled')),
+   scheduled_for timestamptz,
+   created_at timestamptz NOT NULL,
+   updated_at timestamptz NOT NULL,
+   sent_at timestamptz
+);
+-- Lets the scheduler find due issues without scanning every sent one
+CREATE INDEX newsletter_issues_scheduled_idx ON newsletter_issues (scheduled_for) WHERE state = 'scheduled';
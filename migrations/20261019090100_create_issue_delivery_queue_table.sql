@@ -0,0 +1,6 @@
+-- Create Issue Delivery Queue Table
+CREATE TABLE issue_delivery_queue(
+   newsletter_issue_id uuid NOT NULL REFERENCES newsletter_issues (id),
+   subscriber_id uuid NOT NULL REFERENCES subscriptions (id) ON DELETE CASCADE,
+   PRIMARY KEY (newsletter_issue_id, subscriber_id)
+);
@@ -0,0 +1,35 @@
+-- Tracking can be turned off per issue and per subscriber
+ALTER TABLE newsletter_issues ADD COLUMN tracking_enabled BOOLEAN NOT NULL DEFAULT TRUE;
+ALTER TABLE subscriptions ADD COLUMN allow_tracking BOOLEAN NOT NULL DEFAULT TRUE;
+
+-- One row per email handed to the email provider
+CREATE TABLE issue_deliveries(
+   id uuid NOT NULL,
+   PRIMARY KEY (id),
+   newsletter_issue_id uuid NOT NULL REFERENCES newsletter_issues (id),
+   subscriber_id uuid REFERENCES subscriptions (id) ON DELETE SET NULL,
+   status TEXT NOT NULL,
+   -- NULL when the delivery is not tracked
+   tracking_token TEXT UNIQUE,
+   created_at timestamptz NOT NULL
+);
+CREATE INDEX issue_deliveries_issue_idx ON issue_deliveries (newsletter_issue_id);
+
+-- Every link rewritten to go through `GET /t/c/{token}`
+CREATE TABLE tracked_links(
+   token TEXT NOT NULL,
+   PRIMARY KEY (token),
+   delivery_id uuid NOT NULL REFERENCES issue_deliveries (id) ON DELETE CASCADE,
+   url TEXT NOT NULL
+);
+CREATE INDEX tracked_links_delivery_idx ON tracked_links (delivery_id);
+
+CREATE TABLE tracking_events(
+   id uuid NOT NULL,
+   PRIMARY KEY (id),
+   delivery_id uuid NOT NULL REFERENCES issue_deliveries (id) ON DELETE CASCADE,
+   kind TEXT NOT NULL CHECK (kind IN ('open', 'click')),
+   link_token TEXT REFERENCES tracked_links (token) ON DELETE CASCADE,
+   occurred_at timestamptz NOT NULL
+);
+CREATE INDEX tracking_events_delivery_idx ON tracking_events (delivery_id);
@@ -0,0 +1,13 @@
+-- Addresses we must not email again, whatever their subscription says
+CREATE TABLE suppressions(
+   -- Stored lowercased
+   email TEXT NOT NULL,
+   PRIMARY KEY (email),
+   reason TEXT NOT NULL CHECK (reason IN ('hard_bounce', 'soft_bounce', 'complaint')),
+   created_at timestamptz NOT NULL
+);
+
+ALTER TABLE subscriptions ADD COLUMN status TEXT NOT NULL DEFAULT 'active'
+   CHECK (status IN ('active', 'bounced', 'complained'));
+-- Consecutive soft bounces, reset by a successful delivery
+ALTER TABLE subscriptions ADD COLUMN soft_bounce_count INTEGER NOT NULL DEFAULT 0;
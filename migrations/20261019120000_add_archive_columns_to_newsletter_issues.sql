@@ -0,0 +1,6 @@
+-- Sent issues are published at `/archive/{slug}` unless marked private
+ALTER TABLE newsletter_issues ADD COLUMN slug TEXT;
+UPDATE newsletter_issues SET slug = id::text;
+ALTER TABLE newsletter_issues ALTER COLUMN slug SET NOT NULL;
+ALTER TABLE newsletter_issues ADD CONSTRAINT newsletter_issues_slug_key UNIQUE (slug);
+ALTER TABLE newsletter_issues ADD COLUMN private BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,8 @@
+-- Users allowed to use the admin API, each with its own API token
+CREATE TABLE admins(
+   user_id VARCHAR(48) NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+   PRIMARY KEY (user_id),
+   -- SHA-256 of the token, hex encoded; the token itself is only shown once
+   token_hash TEXT NOT NULL UNIQUE,
+   created_at timestamptz NOT NULL
+);
@@ -0,0 +1,6 @@
+-- When each delivery worker was last seen alive, for the readiness probe
+CREATE TABLE worker_heartbeats(
+   worker_id uuid NOT NULL,
+   PRIMARY KEY (worker_id),
+   last_seen_at timestamptz NOT NULL
+);
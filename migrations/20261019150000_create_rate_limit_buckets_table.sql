@@ -0,0 +1,10 @@
+-- Token buckets of the rate limiter, when kept in Postgres
+CREATE TABLE rate_limit_buckets(
+   key TEXT NOT NULL,
+   PRIMARY KEY (key),
+   tokens DOUBLE PRECISION NOT NULL,
+   updated_at timestamptz NOT NULL,
+   -- Buckets full again hold no information, and can be dropped
+   full_at timestamptz NOT NULL
+);
+CREATE INDEX rate_limit_buckets_full_at ON rate_limit_buckets (full_at);
@@ -0,0 +1,11 @@
+-- Names the subscriber in unsubscribe links without giving away their id;
+-- subscribers from before this migration get one with their next email
+ALTER TABLE subscriptions ADD COLUMN unsubscribe_token TEXT UNIQUE;
+
+-- Unsubscribed addresses go on the suppression list too
+ALTER TABLE suppressions DROP CONSTRAINT suppressions_reason_check;
+ALTER TABLE suppressions ADD CONSTRAINT suppressions_reason_check
+   CHECK (reason IN ('hard_bounce', 'soft_bounce', 'complaint', 'unsubscribed'));
+ALTER TABLE subscriptions DROP CONSTRAINT subscriptions_status_check;
+ALTER TABLE subscriptions ADD CONSTRAINT subscriptions_status_check
+   CHECK (status IN ('active', 'bounced', 'complained', 'unsubscribed'));
@@ -0,0 +1,26 @@
+/// What this binary was built from, and how, as `build.rs` recorded it.
+#[derive(serde::Serialize, Clone, Debug)]
+pub struct BuildInfo {
+    pub version: &'static str,
+    pub git_commit: &'static str,
+    // RFC 3339, in UTC
+    pub build_timestamp: &'static str,
+    pub rustc: &'static str,
+    pub features: Vec<&'static str>,
+}
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+pub const GIT_COMMIT: &str = env!("GIT_COMMIT");
+pub const BUILD_TIMESTAMP: &str = env!("BUILD_TIMESTAMP");
+pub const RUSTC_VERSION: &str = env!("RUSTC_VERSION");
+const CARGO_FEATURES: &str = env!("CARGO_FEATURES");
+
+pub fn build_info() -> BuildInfo {
+    BuildInfo {
+        version: VERSION,
+        git_commit: GIT_COMMIT,
+        build_timestamp: BUILD_TIMESTAMP,
+        rustc: RUSTC_VERSION,
+        features: CARGO_FEATURES.split(',').filter(|feature| !feature.is_empty()).collect(),
+    }
+}
@@ -1,14 +1,50 @@
+use crate::cors::CorsPolicy;
+use crate::email_client::EmailClient;
+use crate::rate_limit::parse_trusted_proxies;
+use crate::security_headers::SecurityHeaders;
+use crate::telemetry::PiiPolicy;
+use crate::templating::{NewsletterLayout, TemplateError};
+use crate::tls::load_certified_key;
 use secrecy::Secret;
 use secrecy::ExposeSecret;
-use serde_aux::field_attributes::deserialize_number_from_string;
+use serde_aux::field_attributes::{deserialize_number_from_string, deserialize_option_number_from_string};
 use sqlx::postgres::PgConnectOptions;
 use sqlx::postgres::PgSslMode;
 use sqlx::ConnectOptions;
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use config::Source;
+use url::Url;
+
+// Every `Secret<String>` in `Settings`, by dotted path. These can be read
+// from a file with a `_FILE` variable and are scrubbed from the logs.
+const SECRET_SETTINGS: [&str; 4] = [
+    "application.session_key",
+    "database.password",
+    "email_client.authorization_token",
+    "email_events.webhook_secret",
+];
+
+// Sessions are signed with HMAC-SHA512, whose key should be at least a full block
+const MIN_SESSION_KEY_LENGTH: usize = 64;
+
+// Committed in `configuration/base.yaml` for development, so anyone can read it
+const DEVELOPMENT_SESSION_KEY: &str = "super-long-and-secret-random-key-needed-to-verify-message-integrity";
 
 #[derive(serde::Deserialize)]
 pub struct Settings {
+    // Not read from the files: it picks which files are read
+    #[serde(skip)]
+    pub environment: Environment,
     pub database: DatabaseSettings,
     pub application: ApplicationSettings,
+    pub email_client: EmailClientSettings,
+    pub newsletter: NewsletterSettings,
+    pub email_events: EmailEventsSettings,
+    pub cors: CorsSettings,
+    pub security_headers: SecurityHeadersSettings,
+    pub rate_limit: RateLimitSettings,
+    pub telemetry: TelemetrySettings,
 }
 
 #[derive(serde::Deserialize)]
@@ -16,6 +52,328 @@ pub struct ApplicationSettings {
     #[serde(deserialize_with = "deserialize_number_from_string")]
     pub port: u16,
     pub host: String,
+    // Public address of the API, used to build links in outgoing emails
+    pub base_url: String,
+    // Signs session cookies
+    pub session_key: Secret<String>,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub shutdown_grace_period_seconds: u64,
+    // Serve `/metrics` on this port instead of the public one
+    #[serde(default, deserialize_with = "deserialize_option_number_from_string")]
+    pub metrics_port: Option<u16>,
+    // Serve HTTPS on `port` instead of plain HTTP
+    #[serde(default)]
+    pub tls: Option<TlsSettings>,
+}
+
+/// HTTPS, for deployments without a proxy in front to terminate TLS.
+#[derive(serde::Deserialize)]
+pub struct TlsSettings {
+    // PEM files: the certificate chain, leaf first, and its private key
+    pub cert_path: String,
+    pub key_path: String,
+    // How often the files are checked for a renewed certificate
+    #[serde(default = "default_tls_reload_interval", deserialize_with = "deserialize_number_from_string")]
+    pub reload_interval_seconds: u64,
+    // Redirect plain HTTP on this port to HTTPS
+    #[serde(default, deserialize_with = "deserialize_option_number_from_string")]
+    pub redirect_port: Option<u16>,
+    // `Strict-Transport-Security` is only sent if set
+    #[serde(default, deserialize_with = "deserialize_option_number_from_string")]
+    pub hsts_max_age_seconds: Option<u64>,
+}
+
+fn default_tls_reload_interval() -> u64 {
+    60
+}
+
+impl Settings {
+    /// The values of every secret setting, for the logs to be scrubbed of.
+    pub fn secrets(&self) -> Vec<Secret<String>> {
+        // Keep in sync with `SECRET_SETTINGS`
+        vec![
+            self.application.session_key.clone(),
+            self.database.password.clone(),
+            self.email_client.authorization_token.clone(),
+            self.email_events.webhook_secret.clone(),
+        ]
+    }
+
+    /// Check the settings for values that parse but make no sense, most of
+    /// them only in production. Every problem is reported, not just the first.
+    pub fn validate(&self) -> Result<(), InvalidSettings> {
+        let mut problems = Vec::new();
+        if let Environment::Production = self.environment {
+            if self.application.port == 0 {
+                problems.push("application.port is 0, so the OS would pick a random port.".to_string());
+            }
+            if !self.database.require_ssl {
+                problems.push("database.require_ssl is false.".to_string());
+            }
+            if self.telemetry.pii == PiiPolicy::Full {
+                problems.push("telemetry.pii is full, so personal data would be logged as is.".to_string());
+            }
+            if self.application.session_key.expose_secret() == DEVELOPMENT_SESSION_KEY {
+                problems.push("application.session_key is the development key committed in base.yaml.".to_string());
+            }
+            // Every tracking and unsubscribe link in the emails starts with it
+            match Url::parse(&self.application.base_url) {
+                Ok(url) if !points_at_this_machine(&url) => {}
+                _ => problems.push(format!(
+                    "application.base_url ({}) is not a public URL, so links in emails would not work.",
+                    self.application.base_url
+                )),
+            }
+        }
+        if !self.email_client.sender_email.contains('@') {
+            problems.push("email_client.sender_email is missing or not an email address.".to_string());
+        }
+        if let Err(cors_problems) = CorsPolicy::from_settings(&self.cors) {
+            problems.extend(cors_problems);
+        }
+        if let Err(header_problems) = SecurityHeaders::from_settings(&self.security_headers) {
+            problems.extend(header_problems);
+        }
+        if let Err(proxy_problems) = parse_trusted_proxies(&self.rate_limit.trusted_proxies) {
+            problems.extend(proxy_problems);
+        }
+        for (name, bucket) in [
+            ("per_ip", &self.rate_limit.per_ip),
+            ("per_email", &self.rate_limit.per_email),
+            ("per_user", &self.rate_limit.per_user),
+        ] {
+            if bucket.capacity == 0 || bucket.refill_every_milliseconds == 0 {
+                problems.push(format!(
+                    "rate_limit.{} needs a capacity and refill_every_milliseconds above 0.",
+                    name
+                ));
+            }
+        }
+        if self.database.min_connections > self.database.max_connections {
+            problems.push(format!(
+                "database.min_connections ({}) is more than database.max_connections ({}).",
+                self.database.min_connections, self.database.max_connections
+            ));
+        }
+        if self.database.startup_retry.max_attempts == 0 {
+            problems.push("database.startup_retry.max_attempts is 0, so the database would never be tried.".to_string());
+        }
+        if let Some(endpoint) = &self.telemetry.otlp_endpoint {
+            if !endpoint.starts_with("http://") && !endpoint.starts_with("https://") {
+                problems.push(format!("telemetry.otlp_endpoint ({}) is not an http(s) URL.", endpoint));
+            }
+        }
+        if let Some(tls) = &self.application.tls {
+            if let Err(e) = load_certified_key(&tls.cert_path, &tls.key_path) {
+                problems.push(format!("application.tls has no usable certificate: {}.", e));
+            }
+            if tls.redirect_port == Some(self.application.port) && self.application.port != 0 {
+                problems.push("application.tls.redirect_port is application.port, so HTTP and HTTPS can't both be served.".to_string());
+            }
+            if tls.reload_interval_seconds == 0 {
+                problems.push("application.tls.reload_interval_seconds is 0.".to_string());
+            }
+        }
+        let session_key_length = self.application.session_key.expose_secret().len();
+        if session_key_length < MIN_SESSION_KEY_LENGTH {
+            problems.push(format!(
+                "application.session_key is {} bytes long, it needs to be at least {}.",
+                session_key_length, MIN_SESSION_KEY_LENGTH
+            ));
+        }
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(InvalidSettings {
+                environment: self.environment.as_str(),
+                problems,
+            })
+        }
+    }
+}
+
+fn points_at_this_machine(url: &Url) -> bool {
+    match url.host() {
+        Some(url::Host::Domain(domain)) => domain == "localhost",
+        Some(url::Host::Ipv4(address)) => address.is_loopback() || address.is_unspecified(),
+        Some(url::Host::Ipv6(address)) => address.is_loopback() || address.is_unspecified(),
+        None => true,
+    }
+}
+
+/// Everything [`Settings::validate`] found wrong.
+pub struct InvalidSettings {
+    environment: &'static str,
+    problems: Vec<String>,
+}
+
+impl std::fmt::Display for InvalidSettings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "The {} configuration is invalid:", self.environment)?;
+        for problem in &self.problems {
+            write!(f, "\n  - {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for InvalidSettings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Display::fmt(self, f)
+    }
+}
+
+impl std::error::Error for InvalidSettings {}
+
+#[derive(serde::Deserialize)]
+pub struct CorsSettings {
+    // Exact origins such as `https://app.example.com`, `https://*.example.com`
+    // for any of its subdomains, or `*` for any origin at all
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    pub allowed_headers: Vec<String>,
+    // Whether browsers may send cookies and `Authorization` along
+    pub allow_credentials: bool,
+    // How long browsers may cache a preflight response
+    pub max_age_seconds: Option<usize>,
+}
+
+#[derive(serde::Deserialize)]
+pub struct SecurityHeadersSettings {
+    // For routes in none of the groups, such as the JSON API
+    pub default: SecurityPolicySettings,
+    // A request gets the first group with a path prefix matching its path
+    #[serde(default)]
+    pub groups: Vec<RouteGroupSettings>,
+}
+
+#[derive(serde::Deserialize)]
+pub struct RouteGroupSettings {
+    pub name: String,
+    // `/archive` covers `/archive` and `/archive/{slug}`, not `/archives`
+    pub path_prefixes: Vec<String>,
+    #[serde(flatten)]
+    pub policy: SecurityPolicySettings,
+}
+
+/// The security headers of a route group; the ones not set are not sent.
+#[derive(serde::Deserialize)]
+pub struct SecurityPolicySettings {
+    // Every `{nonce}` is replaced with a nonce fresh for each response
+    #[serde(default)]
+    pub content_security_policy: Option<String>,
+    #[serde(default)]
+    pub frame_options: Option<String>,
+    #[serde(default)]
+    pub referrer_policy: Option<String>,
+    #[serde(default)]
+    pub permissions_policy: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+pub struct RateLimitSettings {
+    #[serde(default)]
+    pub store: RateLimitStore,
+    // Proxies whose `X-Forwarded-For` is believed, as addresses such as
+    // `10.0.0.1` or ranges such as `10.0.0.0/8`
+    #[serde(default)]
+    pub trusted_proxies: Vec<String>,
+    // Every request but the health checks and `/metrics`, per client IP
+    pub per_ip: BucketSettings,
+    // Subscriptions, per email address
+    pub per_email: BucketSettings,
+    // Requests by an authenticated admin, per admin
+    pub per_user: BucketSettings,
+}
+
+/// Where the token buckets are kept.
+#[derive(serde::Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RateLimitStore {
+    // Each instance counts on its own
+    #[default]
+    Memory,
+    // Instances share their buckets
+    Postgres,
+}
+
+/// A token bucket: up to `capacity` requests in a burst, and one more for
+/// every `refill_every_milliseconds` after that.
+#[derive(serde::Deserialize, Clone, Copy, Debug)]
+pub struct BucketSettings {
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub capacity: u32,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub refill_every_milliseconds: u64,
+}
+
+#[derive(serde::Deserialize)]
+pub struct TelemetrySettings {
+    // How spans are named in the tracing backend
+    pub service_name: String,
+    // Spans are only exported if set, e.g. `http://localhost:4317` for gRPC
+    // or `http://localhost:4318` for HTTP
+    #[serde(default)]
+    pub otlp_endpoint: Option<String>,
+    #[serde(default)]
+    pub otlp_protocol: OtlpProtocol,
+    // How email addresses and names show up in logs and traces
+    #[serde(default)]
+    pub pii: PiiPolicy,
+}
+
+/// The transport spans are exported to the OTLP collector over.
+#[derive(serde::Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum OtlpProtocol {
+    #[default]
+    Grpc,
+    Http,
+}
+
+#[derive(serde::Deserialize)]
+pub struct EmailClientSettings {
+    pub base_url: String,
+    pub sender_email: String,
+    pub authorization_token: Secret<String>,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub timeout_milliseconds: u64,
+}
+
+impl EmailClientSettings {
+    pub fn client(&self) -> EmailClient {
+        let timeout = std::time::Duration::from_millis(self.timeout_milliseconds);
+        EmailClient::new(
+            self.base_url.clone(),
+            self.sender_email.clone(),
+            self.authorization_token.clone(),
+            timeout,
+        )
+    }
+}
+
+#[derive(serde::Deserialize)]
+pub struct NewsletterSettings {
+    // Shown in the public archive and feeds
+    pub title: String,
+    // Layouts every issue is wrapped in, relative to the working directory
+    pub html_layout_path: String,
+    pub text_layout_path: String,
+}
+
+impl NewsletterSettings {
+    pub fn layout(&self) -> Result<NewsletterLayout, TemplateError> {
+        NewsletterLayout::from_files(&self.html_layout_path, &self.text_layout_path)
+    }
+}
+
+#[derive(serde::Deserialize)]
+pub struct EmailEventsSettings {
+    // Shared with the email provider to sign webhook requests
+    pub webhook_secret: Secret<String>,
+    // Consecutive soft bounces before an address is suppressed
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub soft_bounce_threshold: u32,
 }
 
 #[derive(serde::Deserialize)]
@@ -28,48 +386,408 @@ pub struct DatabaseSettings {
     pub database_name: String,
     // Determine if we demand the connection to be encrypted or not
     pub require_ssl: bool,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_connections: u32,
+    // Connections kept open even when idle
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub min_connections: u32,
+    // How long a query waits for a free connection before failing
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub acquire_timeout_milliseconds: u64,
+    // Unset to keep idle connections, or connections of any age, forever
+    #[serde(default, deserialize_with = "deserialize_option_number_from_string")]
+    pub idle_timeout_seconds: Option<u64>,
+    #[serde(default, deserialize_with = "deserialize_option_number_from_string")]
+    pub max_lifetime_seconds: Option<u64>,
+    // Postgres cancels statements running for longer than this
+    #[serde(default, deserialize_with = "deserialize_option_number_from_string")]
+    pub statement_timeout_milliseconds: Option<u64>,
+    // Statements running for longer than this are logged as warnings, with
+    // their SQL; none are if not set
+    #[serde(default, deserialize_with = "deserialize_option_number_from_string")]
+    pub log_slow_statements_milliseconds: Option<u64>,
+    pub startup_retry: RetrySettings,
+    // Apply pending migrations once the database is reachable. Off by
+    // default: most deployments migrate in a release step instead.
+    #[serde(default)]
+    pub migrate_on_startup: bool,
 }
 
-pub fn get_configuration() -> Result<Settings, config::ConfigError> {
-    // Initialize our configuration reader
-    let mut settings = config::Config::default();
-    
+/// How long to keep trying to reach the database at startup.
+#[derive(Clone, serde::Deserialize)]
+pub struct RetrySettings {
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_attempts: u32,
+    // Doubled after every failed attempt, up to the maximum
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub initial_backoff_milliseconds: u64,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_backoff_milliseconds: u64,
+}
+
+/// Where each configuration value came from, keyed by its dotted path
+/// (e.g. `database.port`).
+pub type ConfigurationSources = BTreeMap<String, String>;
+
+/// The configuration could not be loaded. Besides what went wrong, it lists
+/// the source of every value that had been read, since a bad value is as
+/// likely to come from a forgotten environment variable as from a file.
+pub struct ConfigurationError {
+    message: String,
+    sources: ConfigurationSources,
+}
+
+impl ConfigurationError {
+    fn new(message: impl std::fmt::Display, sources: &ConfigurationSources) -> Self {
+        Self {
+            message: message.to_string(),
+            sources: sources.clone(),
+        }
+    }
+}
+
+impl std::fmt::Display for ConfigurationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)?;
+        if !self.sources.is_empty() {
+            write!(f, "\n\nValues were read from:")?;
+            for (key, source) in &self.sources {
+                write!(f, "\n  {}: {}", key, source)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// `main` reports it through `expect`, which prints the `Debug` output
+impl std::fmt::Debug for ConfigurationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Display::fmt(self, f)
+    }
+}
+
+impl std::error::Error for ConfigurationError {}
+
+/// Load the settings from `configuration/` and the process environment.
+///
+/// Values are layered, each source overriding the ones before it:
+/// 1. `configuration/base.yaml`;
+/// 2. `configuration/{environment}.yaml`, where the environment is taken
+///    from `APP_ENVIRONMENT` and defaults to `local`;
+/// 3. the variables set by platforms such as Heroku: `DATABASE_URL`
+///    (host, port, username, password, database name and, if it has an
+///    `sslmode`, `require_ssl`) and `PORT` (`application.port`);
+/// 4. `APP_`-prefixed variables, with `__` separating the sections:
+///    `APP_APPLICATION__PORT=5001` sets `application.port`.
+pub fn get_configuration() -> Result<Settings, ConfigurationError> {
+    let base_path = std::env::current_dir().expect("Failed to determine the current directory");
+    get_configuration_from(&base_path.join("configuration"), std::env::vars())
+}
+
+/// [`effective_configuration_from`] the same files and variables as
+/// [`get_configuration`].
+pub fn effective_configuration() -> Result<Vec<ConfiguredValue>, ConfigurationError> {
     let base_path = std::env::current_dir().expect("Failed to determine the current directory");
-    let configuration_directory = base_path.join("configuration");
+    effective_configuration_from(&base_path.join("configuration"), std::env::vars())
+}
+
+/// [`get_configuration`], reading the files from `directory` and the
+/// variables from `variables` instead of the process environment.
+pub fn get_configuration_from(
+    directory: &Path,
+    variables: impl IntoIterator<Item = (String, String)>,
+) -> Result<Settings, ConfigurationError> {
+    load(directory, variables).map(|(settings, _)| settings)
+}
 
-    // Add configuration values from a file named `configuration`.
-    // It will look for any top-level file with an extension
-    // that `config` knows how to parse: yaml, json, etc.
-    settings.merge(config::File::from(configuration_directory.join("base")).required(true))?;
+/// One value of the effective configuration, as `config print` shows it.
+#[derive(Debug, PartialEq)]
+pub struct ConfiguredValue {
+    pub key: String,
+    // `[REDACTED]` for secrets
+    pub value: String,
+    pub source: String,
+}
+
+/// Every value the configuration is made of, sorted by key, with secrets
+/// redacted and the source each value was read from.
+pub fn effective_configuration_from(
+    directory: &Path,
+    variables: impl IntoIterator<Item = (String, String)>,
+) -> Result<Vec<ConfiguredValue>, ConfigurationError> {
+    let variables: HashMap<String, String> = variables.into_iter().collect();
+    let environment_source = if variables.contains_key("APP_ENVIRONMENT") {
+        "the APP_ENVIRONMENT environment variable"
+    } else {
+        "the default"
+    };
+    let (settings, mut loaded) = load(directory, variables)?;
+    let mut values = BTreeMap::new();
+    values.insert("environment".to_string(), settings.environment.as_str().to_string());
+    loaded.sources.insert("environment".to_string(), environment_source.to_string());
+    flatten_values(&mut values, "", loaded.values);
+    Ok(values
+        .into_iter()
+        .map(|(key, value)| {
+            let value = if SECRET_SETTINGS.contains(&key.as_str()) {
+                "[REDACTED]".to_string()
+            } else {
+                value
+            };
+            let source = loaded
+                .sources
+                .get(&key)
+                .cloned()
+                .unwrap_or_else(|| "unknown".to_string());
+            ConfiguredValue { key, value, source }
+        })
+        .collect())
+}
+
+// What `load` read on the way to the settings
+struct LoadedValues {
+    values: HashMap<String, config::Value>,
+    sources: ConfigurationSources,
+}
+
+fn load(
+    directory: &Path,
+    variables: impl IntoIterator<Item = (String, String)>,
+) -> Result<(Settings, LoadedValues), ConfigurationError> {
+    let variables: HashMap<String, String> = variables.into_iter().collect();
+    let mut settings = config::Config::default();
+    let mut sources = ConfigurationSources::new();
 
     // Detect the environment
     // Default to `local` if unspecified
-    let environment: Environment = std::env::var("APP_ENVIRONMENT")
-        .unwrap_or_else(|_| "local".into())
+    let environment: Environment = variables
+        .get("APP_ENVIRONMENT")
+        .cloned()
+        .unwrap_or_else(|| "local".into())
         .try_into()
-        .expect("Failed to parse APP_ENVIRONMENT.");
-    // Layer on the environment-specific values.
-    settings.merge(
-        config::File::from(configuration_directory.join(environment.as_str())).required(true),
-    )?;
-    // Add in settings from environment variables (with a prefix of APP and '__' as separator)
-    // E.g. `APP_APPLICATION__PORT=5001 would set `Settings.application.port`
-    settings.merge(config::Environment::with_prefix("app").separator("__"))?;
-    // settings.merge(
+        .map_err(|e| ConfigurationError::new(format!("Failed to parse APP_ENVIRONMENT: {}", e), &sources))?;
+
+    // The base values, then the environment-specific ones on top
+    for name in ["base", environment.as_str()] {
+        let path = directory.join(format!("{}.yaml", name));
+        let file = config::File::from(path.as_path()).required(true);
+        let values = file
+            .collect()
+            .map_err(|e| ConfigurationError::new(e, &sources))?;
+        record_sources(&mut sources, "", values, &path.display().to_string());
+        settings
+            .merge(file)
+            .map_err(|e| ConfigurationError::new(e, &sources))?;
+    }
+
+    let platform = platform_overrides(&variables).map_err(|e| ConfigurationError::new(e, &sources))?;
+    platform.record(&mut sources);
+    settings
+        .merge(platform)
+        .map_err(|e| ConfigurationError::new(e, &sources))?;
+
+    let prefixed = prefixed_overrides(&variables).map_err(|e| ConfigurationError::new(e, &sources))?;
+    prefixed.record(&mut sources);
+    settings
+        .merge(prefixed)
+        .map_err(|e| ConfigurationError::new(e, &sources))?;
+
+    let values = settings
+        .collect()
+        .map_err(|e| ConfigurationError::new(e, &sources))?;
     // Try to convert the configuration values it read into
     // our Settings type
-    settings.try_into()
+    let mut settings: Settings = settings
+        .try_into()
+        .map_err(|e| ConfigurationError::new(format!("Invalid configuration: {}", e), &sources))?;
+    settings.environment = environment;
+    Ok((settings, LoadedValues { values, sources }))
+}
+
+fn flatten_values(flattened: &mut BTreeMap<String, String>, prefix: &str, values: HashMap<String, config::Value>) {
+    for (key, value) in values {
+        let key = if prefix.is_empty() {
+            key
+        } else {
+            format!("{}.{}", prefix, key)
+        };
+        if let Ok(table) = value.clone().into_table() {
+            flatten_values(flattened, &key, table);
+        } else {
+            flattened.insert(key, display_value(value));
+        }
+    }
+}
+
+fn display_value(value: config::Value) -> String {
+    // Tables in arrays, such as `security_headers.groups`, are shown inline
+    if let Ok(table) = value.clone().into_table() {
+        let mut fields: Vec<String> = table
+            .into_iter()
+            .map(|(key, value)| format!("{}: {}", key, display_value(value)))
+            .collect();
+        fields.sort();
+        return format!("{{{}}}", fields.join(", "));
+    }
+    if let Ok(array) = value.clone().into_array() {
+        let items: Vec<String> = array.into_iter().map(display_value).collect();
+        return format!("[{}]", items.join(", "));
+    }
+    // Only nulls cannot be shown as a string
+    value.into_str().unwrap_or_else(|_| "null".to_string())
+}
+
+fn record_sources(sources: &mut ConfigurationSources, prefix: &str, values: HashMap<String, config::Value>, source: &str) {
+    for (key, value) in values {
+        let key = if prefix.is_empty() {
+            key
+        } else {
+            format!("{}.{}", prefix, key)
+        };
+        match value.clone().into_table() {
+            Ok(table) => record_sources(sources, &key, table, source),
+            Err(_) => {
+                sources.insert(key, source.to_string());
+            }
+        }
+    }
+}
+
+/// Values taken from environment variables, keyed by dotted path.
+#[derive(Clone, Debug, Default)]
+struct Overrides {
+    values: HashMap<String, config::Value>,
+    sources: HashMap<String, String>,
+}
+
+impl Overrides {
+    fn insert(&mut self, key: &str, value: impl Into<config::Value>, variable: &str) {
+        let source = format!("the {} environment variable", variable);
+        self.insert_with_source(key, value, source);
+    }
+
+    fn insert_with_source(&mut self, key: &str, value: impl Into<config::Value>, source: String) {
+        // Carry the source, so type errors point at it
+        let value = config::Value::new(Some(&source), value.into().kind);
+        self.values.insert(key.to_string(), value);
+        self.sources.insert(key.to_string(), source);
+    }
+
+    fn record(&self, sources: &mut ConfigurationSources) {
+        for (key, source) in &self.sources {
+            sources.insert(key.clone(), source.clone());
+        }
+    }
+}
 
+impl Source for Overrides {
+    fn clone_into_box(&self) -> Box<dyn Source + Send + Sync> {
+        Box::new(self.clone())
+    }
+
+    fn collect(&self) -> Result<HashMap<String, config::Value>, config::ConfigError> {
+        Ok(self.values.clone())
+    }
+}
+
+fn platform_overrides(variables: &HashMap<String, String>) -> Result<Overrides, String> {
+    let mut overrides = Overrides::default();
+    if let Some(port) = variables.get("PORT") {
+        overrides.insert("application.port", port.as_str(), "PORT");
+    }
+    if let Some(url) = variables.get("DATABASE_URL") {
+        // Never echo the URL back: it holds the password
+        let url = Url::parse(url).map_err(|e| format!("DATABASE_URL is not a valid URL: {}", e))?;
+        if !matches!(url.scheme(), "postgres" | "postgresql") {
+            return Err(format!(
+                "DATABASE_URL must be a `postgres://` URL, not `{}://`.",
+                url.scheme()
+            ));
+        }
+        let host = url
+            .host_str()
+            .ok_or("DATABASE_URL does not have a host.")?;
+        overrides.insert("database.host", host, "DATABASE_URL");
+        overrides.insert("database.port", url.port().unwrap_or(5432) as i64, "DATABASE_URL");
+        if !url.username().is_empty() {
+            overrides.insert("database.username", percent_decode(url.username())?, "DATABASE_URL");
+        }
+        if let Some(password) = url.password() {
+            overrides.insert("database.password", percent_decode(password)?, "DATABASE_URL");
+        }
+        let database_name = url.path().trim_start_matches('/');
+        if !database_name.is_empty() {
+            overrides.insert("database.database_name", percent_decode(database_name)?, "DATABASE_URL");
+        }
+        if let Some((_, ssl_mode)) = url.query_pairs().find(|(key, _)| key == "sslmode") {
+            let require_ssl = match ssl_mode.as_ref() {
+                "require" | "verify-ca" | "verify-full" => true,
+                "disable" | "allow" | "prefer" => false,
+                other => return Err(format!("DATABASE_URL has an unknown sslmode `{}`.", other)),
+            };
+            overrides.insert("database.require_ssl", require_ssl, "DATABASE_URL");
+        }
+    }
+    Ok(overrides)
+}
+
+fn percent_decode(value: &str) -> Result<String, String> {
+    percent_encoding::percent_decode_str(value)
+        .decode_utf8()
+        .map(|value| value.into_owned())
+        .map_err(|_| "DATABASE_URL is not valid UTF-8 once decoded.".to_string())
 }
+
+// E.g. `APP_APPLICATION__PORT=5001` sets `application.port`, and for
+// secrets `APP_DATABASE__PASSWORD_FILE=/run/secrets/db` sets
+// `database.password` to the contents of that file
+fn prefixed_overrides(variables: &HashMap<String, String>) -> Result<Overrides, String> {
+    let mut overrides = Overrides::default();
+    for (variable, value) in variables {
+        let key = match variable.to_uppercase().strip_prefix("APP_") {
+            // Picks the files, rather than being a setting itself
+            Some("ENVIRONMENT") | None => continue,
+            Some(key) => key.replace("__", ".").to_lowercase(),
+        };
+        match key.strip_suffix("_file") {
+            Some(secret) if SECRET_SETTINGS.contains(&secret) => {
+                let direct = format!("APP_{}", secret.replace('.', "__").to_uppercase());
+                if variables.keys().any(|v| v.to_uppercase() == direct) {
+                    return Err(format!("Both {} and {} are set, use only one.", direct, variable));
+                }
+                let contents = std::fs::read_to_string(value)
+                    .map_err(|e| format!("Failed to read {} ({}): {}", variable, value, e))?;
+                // Files written by editors and `echo` end with a newline
+                let contents = contents.trim_end_matches(&['\r', '\n'][..]);
+                overrides.insert_with_source(
+                    secret,
+                    contents,
+                    format!("the file at {} ({})", value, variable),
+                );
+            }
+            _ => overrides.insert(&key, value.as_str(), variable),
+        }
+    }
+    Ok(overrides)
+}
+
 /// The possible runtime environment for our application.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
 pub enum Environment {
+    // What `get_configuration` falls back to without `APP_ENVIRONMENT`
+    #[default]
     Local,
+    // What the integration tests run against
+    Test,
+    Staging,
     Production,
 }
 impl Environment {
     pub fn as_str(&self) -> &'static str {
         match self {
             Environment::Local => "local",
+            Environment::Test => "test",
+            Environment::Staging => "staging",
             Environment::Production => "production",
         }
     }
@@ -81,9 +799,11 @@ impl TryFrom<String> for Environment {
     fn try_from(s: String) -> Result<Self, Self::Error> {
         match s.to_lowercase().as_str() {
             "local" => Ok(Self::Local),
+            "test" => Ok(Self::Test),
+            "staging" => Ok(Self::Staging),
             "production" => Ok(Self::Production),
             other => Err(format!(
-                "{} is not a supported environment. Use either `local` or `production`.",
+                "{} is not a supported environment. Use `local`, `test`, `staging` or `production`.",
                 other
             )),
         }
@@ -102,7 +822,7 @@ impl DatabaseSettings {
         PgConnectOptions::new()
             .host(&self.host)
             .username(&self.username)
-            .password(&self.password.expose_secret())
+            .password(self.password.expose_secret())
             .port(self.port)
             .ssl_mode(ssl_mode)
     }
@@ -110,7 +830,18 @@ impl DatabaseSettings {
     // Renamed from `connection_string`
     pub fn with_db(&self) -> PgConnectOptions {
         let mut options = self.without_db().database(&self.database_name);
+        if let Some(timeout) = self.statement_timeout_milliseconds {
+            options = options.options([("statement_timeout", timeout.to_string())]);
+        }
+        // Every statement is there for `sqlx=trace`; slow ones are not hidden
         options.log_statements(tracing::log::LevelFilter::Trace);
+        match self.log_slow_statements_milliseconds {
+            Some(threshold) => options.log_slow_statements(
+                tracing::log::LevelFilter::Warn,
+                std::time::Duration::from_millis(threshold),
+            ),
+            None => options.log_slow_statements(tracing::log::LevelFilter::Off, std::time::Duration::default()),
+        };
         options
     }
 }
\ No newline at end of file
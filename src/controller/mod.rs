@@ -1,3 +1,5 @@
+pub mod newsletter_issue_controller;
 pub mod user_controller;
 
+pub use newsletter_issue_controller::init as init_newsletter_issue_controller;
 pub use user_controller::init as init_user_controller;
\ No newline at end of file
@@ -0,0 +1,166 @@
+use actix_web::{delete, get, post, put, web, HttpResponse, Responder};
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+use uuid::Uuid;
+use crate::models::issue_delivery::IssueStats;
+use crate::models::newsletter_issue::NewsletterIssue;
+use crate::routes::{AuthenticatedAdmin, BodyData};
+use crate::templating::{NewsletterLayout, NewsletterTemplate};
+
+#[derive(serde::Deserialize)]
+pub struct ScheduleFormData {
+    pub scheduled_for: DateTime<Utc>,
+}
+
+#[derive(serde::Deserialize)]
+pub struct VisibilityFormData {
+    pub private: bool,
+}
+
+pub fn init(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_all_issues);
+    cfg.service(post_draft);
+    cfg.service(get_issue);
+    cfg.service(update_issue);
+    cfg.service(delete_draft);
+    cfg.service(schedule_issue);
+    cfg.service(cancel_issue);
+    cfg.service(set_issue_visibility);
+    cfg.service(get_issue_stats);
+}
+
+#[tracing::instrument(name = "Getting all newsletter issues", skip(admin, pool), fields(admin_id = %admin.user_id))]
+#[get("/newsletters")]
+async fn get_all_issues(admin: AuthenticatedAdmin, pool: web::Data<PgPool>) -> impl Responder {
+    match NewsletterIssue::find_all(&pool).await {
+        Err(_) => HttpResponse::InternalServerError().finish(),
+        Ok(issues) => HttpResponse::Ok().json(issues),
+    }
+}
+
+#[tracing::instrument(name = "Adding a newsletter draft", skip(admin, body, pool, layout), fields(admin_id = %admin.user_id, issue_title = %body.title))]
+#[post("/newsletters/drafts")]
+async fn post_draft(
+    admin: AuthenticatedAdmin,
+    body: web::Json<BodyData>,
+    pool: web::Data<PgPool>,
+    layout: web::Data<NewsletterLayout>,
+) -> HttpResponse {
+    if let Err(e) = NewsletterTemplate::compose(&body.title, &body.markdown, &layout) {
+        return HttpResponse::BadRequest().body(e.to_string());
+    }
+    match NewsletterIssue::insert_draft(&pool, &body).await {
+        Ok(issue) => HttpResponse::Ok().json(issue),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+#[tracing::instrument(name = "Getting a single newsletter issue", skip(admin, pool), fields(admin_id = %admin.user_id, issue_id = %issue_id))]
+#[get("/newsletters/{id}")]
+async fn get_issue(admin: AuthenticatedAdmin, issue_id: web::Path<Uuid>, pool: web::Data<PgPool>) -> impl Responder {
+    match NewsletterIssue::get_by_id(&pool, *issue_id).await {
+        Err(_) => HttpResponse::NotFound().finish(),
+        Ok(issue) => HttpResponse::Ok().json(issue),
+    }
+}
+
+#[tracing::instrument(name = "Updating a newsletter issue", skip(admin, body, pool, layout), fields(admin_id = %admin.user_id, issue_id = %issue_id))]
+#[put("/newsletters/{id}")]
+async fn update_issue(
+    admin: AuthenticatedAdmin,
+    issue_id: web::Path<Uuid>,
+    body: web::Json<BodyData>,
+    pool: web::Data<PgPool>,
+    layout: web::Data<NewsletterLayout>,
+) -> HttpResponse {
+    if let Err(e) = NewsletterTemplate::compose(&body.title, &body.markdown, &layout) {
+        return HttpResponse::BadRequest().body(e.to_string());
+    }
+    let issue = NewsletterIssue::update_unsent(&pool, *issue_id, &body).await;
+    unsent_issue_response(&pool, *issue_id, issue).await
+}
+
+#[tracing::instrument(name = "Scheduling a newsletter issue", skip(admin, form, pool), fields(admin_id = %admin.user_id, issue_id = %issue_id, scheduled_for = %form.scheduled_for))]
+#[post("/newsletters/{id}/schedule")]
+async fn schedule_issue(
+    admin: AuthenticatedAdmin,
+    issue_id: web::Path<Uuid>,
+    form: web::Json<ScheduleFormData>,
+    pool: web::Data<PgPool>,
+) -> HttpResponse {
+    if form.scheduled_for <= Utc::now() {
+        return HttpResponse::BadRequest().body("`scheduled_for` must be in the future.");
+    }
+    let issue = NewsletterIssue::schedule(&pool, *issue_id, form.scheduled_for).await;
+    unsent_issue_response(&pool, *issue_id, issue).await
+}
+
+#[tracing::instrument(name = "Cancelling a newsletter issue", skip(admin, pool), fields(admin_id = %admin.user_id, issue_id = %issue_id))]
+#[post("/newsletters/{id}/cancel")]
+async fn cancel_issue(admin: AuthenticatedAdmin, issue_id: web::Path<Uuid>, pool: web::Data<PgPool>) -> HttpResponse {
+    let issue = NewsletterIssue::cancel(&pool, *issue_id).await;
+    unsent_issue_response(&pool, *issue_id, issue).await
+}
+
+#[tracing::instrument(name = "Changing the visibility of a newsletter issue", skip(admin, form, pool), fields(admin_id = %admin.user_id, issue_id = %issue_id, private = %form.private))]
+#[post("/newsletters/{id}/visibility")]
+async fn set_issue_visibility(
+    admin: AuthenticatedAdmin,
+    issue_id: web::Path<Uuid>,
+    form: web::Json<VisibilityFormData>,
+    pool: web::Data<PgPool>,
+) -> HttpResponse {
+    match NewsletterIssue::set_private(&pool, *issue_id, form.private).await {
+        Ok(Some(issue)) => HttpResponse::Ok().json(issue),
+        Ok(None) => HttpResponse::NotFound().finish(),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+#[tracing::instrument(name = "Getting newsletter issue stats", skip(admin, pool), fields(admin_id = %admin.user_id, issue_id = %issue_id))]
+#[get("/newsletters/{id}/stats")]
+async fn get_issue_stats(admin: AuthenticatedAdmin, issue_id: web::Path<Uuid>, pool: web::Data<PgPool>) -> impl Responder {
+    if let Err(e) = NewsletterIssue::get_by_id(&pool, *issue_id).await {
+        return match e {
+            sqlx::Error::RowNotFound => HttpResponse::NotFound().finish(),
+            _ => HttpResponse::InternalServerError().finish(),
+        };
+    }
+    match IssueStats::for_issue(&pool, *issue_id).await {
+        Ok(stats) => HttpResponse::Ok().json(stats),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+#[tracing::instrument(name = "Deleting a newsletter draft", skip(admin, pool), fields(admin_id = %admin.user_id, issue_id = %issue_id))]
+#[delete("/newsletters/{id}")]
+async fn delete_draft(admin: AuthenticatedAdmin, issue_id: web::Path<Uuid>, pool: web::Data<PgPool>) -> HttpResponse {
+    match NewsletterIssue::delete_draft(&pool, *issue_id).await {
+        Ok(true) => HttpResponse::Ok().finish(),
+        Ok(false) => not_found_or_conflict(&pool, *issue_id).await,
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+// Updates that only apply to drafts and scheduled issues match no row
+// once sending has begun; tell that apart from an unknown id.
+async fn unsent_issue_response(
+    pool: &PgPool,
+    issue_id: Uuid,
+    issue: Result<Option<NewsletterIssue>, sqlx::Error>,
+) -> HttpResponse {
+    match issue {
+        Ok(Some(issue)) => HttpResponse::Ok().json(issue),
+        Ok(None) => not_found_or_conflict(pool, issue_id).await,
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+async fn not_found_or_conflict(pool: &PgPool, issue_id: Uuid) -> HttpResponse {
+    match NewsletterIssue::get_by_id(pool, issue_id).await {
+        Ok(issue) => HttpResponse::Conflict()
+            .body(format!("The issue can no longer be changed: it is {}.", issue.state)),
+        Err(sqlx::Error::RowNotFound) => HttpResponse::NotFound().finish(),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
@@ -3,6 +3,8 @@ use sqlx::{PgPool, FromRow, Row, Error, postgres::PgRow};
 use uuid::Uuid;
 use chrono::Utc;
 use crate::{models::user::User};
+use crate::monitoring::TimedQuery;
+use crate::telemetry::Redacted;
 
 #[derive(serde::Deserialize, Debug)]
 pub struct UserFormData {
@@ -64,7 +66,7 @@ async fn get_user(user_id: web::Path<String>, pool: web::Data<PgPool>) -> impl R
         Ok(user) => HttpResponse::Ok().json(user),
     }
 }
-#[tracing::instrument(name = "Updating a single user",skip(pool),fields(user_id = %user_id, user_name = %form.name,user_email = %form.email))]
+#[tracing::instrument(name = "Updating a single user",skip(pool, form),fields(user_id = %user_id, user_name = %Redacted(&form.name),user_email = %Redacted(&form.email)))]
 #[put("/user/{id}")]
 async fn update_user(
     user_id: web::Path<String>, 
@@ -94,7 +96,7 @@ async fn delete_user_by_id(
     }
 }
 
-#[tracing::instrument(name = "Adding a new user",skip(form),fields(user_name = %form.name,user_email = %form.email))]
+#[tracing::instrument(name = "Adding a new user",skip(form),fields(user_name = %Redacted(&form.name),user_email = %Redacted(&form.email)))]
 #[post("/user")]
 async fn post_user(
     // web::Json<UserFormData> to test 
@@ -125,6 +127,7 @@ pub async fn add_user(
         Utc::now(),
     )
     .execute(pool)
+    .timed("user.add_user")
     .await
     .map_err(|e| {
         tracing::error!("Failed to execute query: {:?}", e);
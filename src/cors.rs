@@ -0,0 +1,159 @@
+use crate::configuration::CorsSettings;
+use crate::request_id::REQUEST_ID_HEADER;
+use actix_cors::Cors;
+use actix_web::http::header::HeaderName;
+use actix_web::http::Method;
+use std::sync::Arc;
+use url::Url;
+
+/// An origin the API accepts cross-origin requests from.
+#[derive(Clone, Debug, PartialEq)]
+enum AllowedOrigin {
+    // `*`: any origin at all
+    Any,
+    // E.g. `https://app.example.com`
+    Exact(String),
+    // E.g. `https://*.example.com` is stored as `https://` and
+    // `.example.com`, and matches any subdomain but not the domain itself
+    Subdomains { scheme: String, suffix: String },
+}
+
+impl AllowedOrigin {
+    fn parse(pattern: &str) -> Result<AllowedOrigin, String> {
+        if pattern == "*" {
+            return Ok(AllowedOrigin::Any);
+        }
+        let invalid = |reason: &str| format!("cors.allowed_origins: `{}` {}.", pattern, reason);
+        let (scheme, rest) = pattern
+            .split_once("://")
+            .ok_or_else(|| invalid("is not an origin, e.g. `https://example.com`"))?;
+        let wildcard = rest.starts_with("*.");
+        // `*` is not a valid host, so check the rest of the pattern with a stand-in label
+        let candidate = if wildcard {
+            format!("{}://wildcard{}", scheme, &rest[1..])
+        } else {
+            pattern.to_string()
+        };
+        let url = Url::parse(&candidate).map_err(|e| invalid(&e.to_string()))?;
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(invalid("must use http or https"));
+        }
+        // Origins have no path, query or fragment, nor other wildcards
+        let origin = url.origin().ascii_serialization();
+        if origin != candidate.to_lowercase() {
+            return Err(invalid("must be a bare origin like `https://*.example.com`, without a path"));
+        }
+        if wildcard {
+            Ok(AllowedOrigin::Subdomains {
+                scheme: format!("{}://", url.scheme()),
+                suffix: origin["wildcard".len() + url.scheme().len() + 3..].to_string(),
+            })
+        } else {
+            Ok(AllowedOrigin::Exact(origin))
+        }
+    }
+
+    fn matches(&self, origin: &str) -> bool {
+        match self {
+            AllowedOrigin::Any => true,
+            AllowedOrigin::Exact(allowed) => allowed.eq_ignore_ascii_case(origin),
+            AllowedOrigin::Subdomains { scheme, suffix } => {
+                let origin = origin.to_lowercase();
+                match origin
+                    .strip_prefix(scheme.as_str())
+                    .and_then(|host| host.strip_suffix(suffix.as_str()))
+                {
+                    Some(subdomain) => {
+                        !subdomain.is_empty()
+                            && subdomain
+                                .split('.')
+                                .all(|label| !label.is_empty() && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-'))
+                    }
+                    None => false,
+                }
+            }
+        }
+    }
+}
+
+/// The CORS settings, checked and parsed.
+#[derive(Clone, Debug)]
+pub struct CorsPolicy {
+    origins: Arc<Vec<AllowedOrigin>>,
+    methods: Vec<Method>,
+    headers: Vec<HeaderName>,
+    allow_credentials: bool,
+    max_age_seconds: Option<usize>,
+}
+
+impl CorsPolicy {
+    /// Parse `settings`, reporting every entry that is invalid.
+    pub fn from_settings(settings: &CorsSettings) -> Result<CorsPolicy, Vec<String>> {
+        let mut problems = Vec::new();
+        let mut origins = Vec::new();
+        for pattern in &settings.allowed_origins {
+            match AllowedOrigin::parse(pattern) {
+                Ok(origin) => origins.push(origin),
+                Err(problem) => problems.push(problem),
+            }
+        }
+        if settings.allow_credentials && origins.contains(&AllowedOrigin::Any) {
+            problems.push("cors.allowed_origins: `*` cannot be combined with cors.allow_credentials.".to_string());
+        }
+        let mut methods = Vec::new();
+        for method in &settings.allowed_methods {
+            match Method::from_bytes(method.to_uppercase().as_bytes()) {
+                Ok(method) => methods.push(method),
+                Err(_) => problems.push(format!("cors.allowed_methods: `{}` is not an HTTP method.", method)),
+            }
+        }
+        let mut headers = Vec::new();
+        for header in &settings.allowed_headers {
+            match HeaderName::from_bytes(header.as_bytes()) {
+                Ok(header) => headers.push(header),
+                Err(_) => problems.push(format!("cors.allowed_headers: `{}` is not a header name.", header)),
+            }
+        }
+        if problems.is_empty() {
+            Ok(CorsPolicy {
+                origins: Arc::new(origins),
+                methods,
+                headers,
+                allow_credentials: settings.allow_credentials,
+                max_age_seconds: settings.max_age_seconds,
+            })
+        } else {
+            Err(problems)
+        }
+    }
+
+    /// The middleware enforcing the policy, built once per worker.
+    pub fn middleware(&self) -> Cors {
+        let mut cors = Cors::default();
+        if self.origins.contains(&AllowedOrigin::Any) {
+            cors = cors.allow_any_origin();
+            // Credentialed responses must name the origin
+            if !self.allow_credentials {
+                cors = cors.send_wildcard();
+            }
+        } else {
+            let origins = self.origins.clone();
+            cors = cors.allowed_origin_fn(move |origin, _request| match origin.to_str() {
+                Ok(origin) => origins.iter().any(|allowed| allowed.matches(origin)),
+                Err(_) => false,
+            });
+        }
+        if !self.methods.is_empty() {
+            cors = cors.allowed_methods(self.methods.clone());
+        }
+        if !self.headers.is_empty() {
+            cors = cors.allowed_headers(self.headers.clone());
+        }
+        if self.allow_credentials {
+            cors = cors.supports_credentials();
+        }
+        // For browser clients to quote when reporting a problem
+        cors = cors.expose_headers([REQUEST_ID_HEADER]);
+        cors.max_age(self.max_age_seconds)
+    }
+}
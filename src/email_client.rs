@@ -0,0 +1,84 @@
+use crate::request_id::{RequestId, REQUEST_ID_HEADER};
+use crate::telemetry::trace_context_headers;
+use reqwest::Client;
+use secrecy::{ExposeSecret, Secret};
+use std::collections::BTreeMap;
+
+/// Key/value pairs the email provider echoes back in its webhooks.
+pub type Metadata<'a> = BTreeMap<&'a str, String>;
+
+pub struct EmailClient {
+    http_client: Client,
+    base_url: String,
+    sender: String,
+    // Postmark server token, sent along with every request
+    authorization_token: Secret<String>,
+}
+
+impl EmailClient {
+    pub fn new(
+        base_url: String,
+        sender: String,
+        authorization_token: Secret<String>,
+        timeout: std::time::Duration,
+    ) -> Self {
+        let http_client = Client::builder().timeout(timeout).build().unwrap();
+        Self {
+            http_client,
+            base_url,
+            sender,
+            authorization_token,
+        }
+    }
+
+    pub async fn send_email(
+        &self,
+        recipient: &str,
+        subject: &str,
+        html_content: &str,
+        text_content: &str,
+        metadata: &Metadata<'_>,
+    ) -> Result<(), reqwest::Error> {
+        let url = format!("{}/email", self.base_url);
+        let request_body = SendEmailRequest {
+            from: &self.sender,
+            to: recipient,
+            subject,
+            html_body: html_content,
+            text_body: text_content,
+            metadata,
+        };
+        let mut request = self
+            .http_client
+            .post(&url)
+            // Lets the trace follow the email into the provider, if it traces too
+            .headers(trace_context_headers());
+        // Sent on behalf of a request, rather than by the delivery worker
+        if let Some(request_id) = RequestId::current() {
+            request = request.header(REQUEST_ID_HEADER, request_id.as_str());
+        }
+        request
+            .header(
+                "X-Postmark-Server-Token",
+                self.authorization_token.expose_secret(),
+            )
+            .json(&request_body)
+            .send()
+            .await?
+            // Turn 4xx and 5xx responses into errors
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct SendEmailRequest<'a> {
+    from: &'a str,
+    to: &'a str,
+    subject: &'a str,
+    html_body: &'a str,
+    text_body: &'a str,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    metadata: &'a Metadata<'a>,
+}
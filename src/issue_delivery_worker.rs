@@ -0,0 +1,429 @@
+use crate::configuration::Settings;
+use crate::email_client::{EmailClient, Metadata};
+use crate::models::issue_delivery::{DeliveryStatus, IssueDelivery};
+use crate::models::newsletter_issue::IssueState;
+use crate::monitoring::{timed_begin, TimedQuery};
+use crate::routes::unsubscribe_url;
+use crate::shutdown::Shutdown;
+use crate::startup::ApplicationBaseUrl;
+use crate::templating::{NewsletterLayout, TemplateError, NewsletterTemplate, Personalization};
+use crate::tracking::{new_token, track_html};
+use metrics::increment_counter;
+use sqlx::{PgPool, Postgres, Transaction};
+use std::sync::Mutex;
+use std::time::Duration;
+use tracing::{field::display, Span};
+use uuid::Uuid;
+
+// How often the scheduler looks for issues that are due
+const SCHEDULER_INTERVAL: Duration = Duration::from_secs(10);
+// How long the delivery loop waits before polling an empty queue again
+const EMPTY_QUEUE_BACKOFF: Duration = Duration::from_secs(10);
+// How often, at most, the delivery loop records that it is alive
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+/// A worker not heard from for this long is presumed dead. It must outlast
+/// the longest pause of the delivery loop, an empty queue.
+pub const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub enum ExecutionOutcome {
+    TaskCompleted,
+    EmptyQueue,
+}
+
+/// Everything the worker needs to render and send an issue.
+pub struct DeliveryContext {
+    pub db_pool: PgPool,
+    pub email_client: EmailClient,
+    pub newsletter_layout: NewsletterLayout,
+    pub base_url: ApplicationBaseUrl,
+    // How long the delivery in progress gets to finish on shutdown
+    pub shutdown_grace_period: Duration,
+    // The queue item being delivered, to report it if it is abandoned
+    current_task: Mutex<Option<(Uuid, Uuid)>>,
+    // Identifies this worker's heartbeat
+    worker_id: Uuid,
+}
+
+impl DeliveryContext {
+    /// A context delivering over `db_pool`; in `serve`, the API's own.
+    pub fn build(configuration: &Settings, db_pool: PgPool) -> Result<Self, TemplateError> {
+        Ok(Self {
+            db_pool,
+            email_client: configuration.email_client.client(),
+            newsletter_layout: configuration.newsletter.layout()?,
+            base_url: ApplicationBaseUrl(configuration.application.base_url.clone()),
+            shutdown_grace_period: Duration::from_secs(configuration.application.shutdown_grace_period_seconds),
+            current_task: Mutex::new(None),
+            worker_id: Uuid::new_v4(),
+        })
+    }
+}
+
+/// Run the scheduler and the delivery loop side by side until `shutdown`
+/// is triggered, then give the delivery in progress the grace period to
+/// finish. The pool is left for its owner to close.
+///
+/// A delivery cut off by the grace period is rolled back, so the item stays
+/// queued and is delivered again by the next worker.
+pub async fn run_worker_until_stopped(context: DeliveryContext, shutdown: Shutdown) {
+    let mut work = Box::pin(async {
+        tokio::join!(
+            scheduler_loop(&context.db_pool, &shutdown),
+            delivery_loop(&context, &shutdown)
+        );
+    });
+    tokio::select! {
+        _ = &mut work => {}
+        _ = shutdown.triggered() => {
+            tracing::info!("Stopping the delivery worker.");
+            if tokio::time::timeout(context.shutdown_grace_period, &mut work).await.is_err() {
+                if let Some((issue_id, subscriber_id)) = *context.current_task.lock().unwrap() {
+                    tracing::warn!(
+                        newsletter_issue_id = %issue_id,
+                        subscriber_id = %subscriber_id,
+                        "The grace period ran out mid-delivery. It stays queued and will be retried.",
+                    );
+                }
+            }
+        }
+    }
+    // Rolls back an abandoned delivery, returning its connection to the pool
+    drop(work);
+    tracing::info!("The delivery worker has stopped.");
+}
+
+// Sleep for `duration`, unless shut down first
+async fn pause(duration: Duration, shutdown: &Shutdown) {
+    tokio::select! {
+        _ = tokio::time::sleep(duration) => {}
+        _ = shutdown.triggered() => {}
+    }
+}
+
+async fn scheduler_loop(pool: &PgPool, shutdown: &Shutdown) {
+    while !shutdown.is_triggered() {
+        if let Err(e) = promote_due_issues(pool).await {
+            tracing::error!("Failed to promote due newsletter issues: {:?}", e);
+        }
+        if let Err(e) = complete_delivered_issues(pool).await {
+            tracing::error!("Failed to complete delivered newsletter issues: {:?}", e);
+        }
+        pause(SCHEDULER_INTERVAL, shutdown).await;
+    }
+}
+
+async fn delivery_loop(context: &DeliveryContext, shutdown: &Shutdown) {
+    let mut last_heartbeat: Option<std::time::Instant> = None;
+    // Checked between items only: the one in progress is finished
+    while !shutdown.is_triggered() {
+        if last_heartbeat.is_none_or(|at| at.elapsed() >= HEARTBEAT_INTERVAL) {
+            match record_heartbeat(&context.db_pool, context.worker_id).await {
+                Ok(()) => last_heartbeat = Some(std::time::Instant::now()),
+                Err(e) => tracing::error!("Failed to record the worker heartbeat: {:?}", e),
+            }
+        }
+        let outcome = try_execute_task(context).await;
+        let task = context.current_task.lock().unwrap().take();
+        if outcome.is_err() && task.is_some() {
+            // Rolled back, so the item is still queued and will be tried again
+            increment_counter!("newsletter_delivery_retries_total");
+        }
+        match outcome {
+            Ok(ExecutionOutcome::EmptyQueue) => pause(EMPTY_QUEUE_BACKOFF, shutdown).await,
+            Err(_) => pause(Duration::from_secs(1), shutdown).await,
+            Ok(ExecutionOutcome::TaskCompleted) => {}
+        }
+    }
+}
+
+/// Record that the worker `worker_id` is alive.
+pub async fn record_heartbeat(pool: &PgPool, worker_id: Uuid) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        INSERT INTO worker_heartbeats (worker_id, last_seen_at)
+        VALUES ($1, now())
+        ON CONFLICT (worker_id) DO UPDATE SET last_seen_at = EXCLUDED.last_seen_at
+        "#,
+        worker_id
+    )
+    .execute(pool)
+    .timed("worker.record_heartbeat")
+    .await?;
+    Ok(())
+}
+
+/// How long ago any worker was last seen alive, if one ever was.
+pub async fn time_since_last_heartbeat(pool: &PgPool) -> Result<Option<Duration>, sqlx::Error> {
+    let r = sqlx::query!(
+        r#"SELECT EXTRACT(EPOCH FROM now() - max(last_seen_at))::float8 AS seconds FROM worker_heartbeats"#
+    )
+    .fetch_one(pool)
+    .timed_one("worker.time_since_last_heartbeat")
+    .await?;
+    // Clocks never run backwards in the database, but don't panic if they do
+    Ok(r.seconds.map(|seconds| Duration::from_secs_f64(seconds.max(0.0))))
+}
+
+/// Move every scheduled issue that is due to `sending` and queue it for
+/// every subscriber, returning how many issues were promoted.
+///
+/// This is a single statement: when several workers run it at once, the
+/// row locks taken by the `UPDATE` make all but one of them see the issue
+/// as no longer `scheduled`, so each issue is queued exactly once.
+#[tracing::instrument(name = "Promoting due newsletter issues", skip(pool))]
+pub async fn promote_due_issues(pool: &PgPool) -> Result<u64, sqlx::Error> {
+    let promoted = sqlx::query!(
+        r#"
+        WITH due AS (
+            UPDATE newsletter_issues SET state = $1, updated_at = now()
+            WHERE state = $2 AND scheduled_for <= now()
+            RETURNING id
+        ), queued AS (
+            INSERT INTO issue_delivery_queue (newsletter_issue_id, subscriber_id)
+            SELECT due.id, subscriptions.id FROM due CROSS JOIN subscriptions
+        )
+        SELECT id AS "id!" FROM due
+        "#,
+        IssueState::Sending.as_str(),
+        IssueState::Scheduled.as_str()
+    )
+    .fetch_all(pool)
+    .timed("worker.promote_due_issues")
+    .await?;
+    for issue in &promoted {
+        tracing::info!(newsletter_issue_id = %issue.id, "Queued scheduled newsletter issue for delivery");
+    }
+    Ok(promoted.len() as u64)
+}
+
+/// Mark issues whose queue has been fully drained as `sent`.
+#[tracing::instrument(name = "Completing delivered newsletter issues", skip(pool))]
+pub async fn complete_delivered_issues(pool: &PgPool) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"
+        UPDATE newsletter_issues SET state = $1, sent_at = now(), updated_at = now()
+        WHERE state = $2 AND NOT EXISTS (
+            SELECT 1 FROM issue_delivery_queue
+            WHERE newsletter_issue_id = newsletter_issues.id
+        )
+        "#,
+        IssueState::Sent.as_str(),
+        IssueState::Sending.as_str()
+    )
+    .execute(pool)
+    .timed("worker.complete_delivered_issues")
+    .await?;
+    Ok(result.rows_affected())
+}
+
+#[tracing::instrument(
+    skip_all,
+    fields(
+        newsletter_issue_id=tracing::field::Empty,
+        subscriber_id=tracing::field::Empty
+    ),
+    err
+)]
+pub async fn try_execute_task(context: &DeliveryContext) -> Result<ExecutionOutcome, sqlx::Error> {
+    let task = dequeue_task(&context.db_pool).await?;
+    let (transaction, issue_id, subscriber_id) = match task {
+        Some(task) => task,
+        None => return Ok(ExecutionOutcome::EmptyQueue),
+    };
+    *context.current_task.lock().unwrap() = Some((issue_id, subscriber_id));
+    Span::current()
+        .record("newsletter_issue_id", &display(issue_id))
+        .record("subscriber_id", &display(subscriber_id));
+    let issue = get_issue(&context.db_pool, issue_id).await?;
+    let subscriber = get_subscriber(&context.db_pool, subscriber_id).await?;
+    if subscriber.suppressed {
+        // Bounced or complained after the issue was queued
+        tracing::info!("Skipping a suppressed subscriber.");
+        increment_counter!("newsletter_deliveries_total", "outcome" => "suppressed");
+        delete_task(transaction, issue_id, subscriber_id).await?;
+        return Ok(ExecutionOutcome::TaskCompleted);
+    }
+    match NewsletterTemplate::compose(&issue.title, &issue.markdown, &context.newsletter_layout) {
+        Ok(newsletter) => {
+            let unsubscribe_token = match &subscriber.unsubscribe_token {
+                Some(unsubscribe_token) => unsubscribe_token.clone(),
+                None => assign_unsubscribe_token(&context.db_pool, subscriber_id).await?,
+            };
+            let unsubscribe_url = unsubscribe_url(&context.base_url, &unsubscribe_token);
+            let mut rendered = newsletter.render(&Personalization {
+                name: &subscriber.name,
+                unsubscribe_url: &unsubscribe_url,
+            });
+            let delivery_id = Uuid::new_v4();
+            let tracking_token = (issue.tracking_enabled && subscriber.allow_tracking).then(new_token);
+            let mut tracked_links = Vec::new();
+            if let Some(tracking_token) = &tracking_token {
+                let tracked = track_html(
+                    &rendered.html,
+                    &context.base_url,
+                    tracking_token,
+                    &unsubscribe_url,
+                );
+                rendered.html = tracked.html;
+                tracked_links = tracked.links;
+            }
+            IssueDelivery::insert_pending(
+                &context.db_pool,
+                delivery_id,
+                issue_id,
+                subscriber_id,
+                tracking_token.as_deref(),
+                &tracked_links,
+            )
+            .await?;
+            let metadata = Metadata::from([("delivery_id", delivery_id.to_string())]);
+            let status = match context
+                .email_client
+                .send_email(
+                    &subscriber.email,
+                    &issue.title,
+                    &rendered.html,
+                    &rendered.text,
+                    &metadata,
+                )
+                .await
+            {
+                Ok(()) => DeliveryStatus::Sent,
+                Err(e) => {
+                    tracing::error!(
+                        error.cause_chain = ?e,
+                        "Failed to deliver issue to a subscriber. Skipping.",
+                    );
+                    DeliveryStatus::Failed
+                }
+            };
+            increment_counter!("newsletter_deliveries_total", "outcome" => status.as_str());
+            IssueDelivery::set_status(&context.db_pool, delivery_id, status).await?;
+        }
+        // Issues are validated when they are saved, so this only happens
+        // if the layout changed underneath a queued issue.
+        Err(e) => {
+            tracing::error!(
+                error.cause_chain = ?e,
+                "Failed to render issue for a subscriber. Skipping.",
+            );
+            increment_counter!("newsletter_deliveries_total", "outcome" => "render_failed");
+        }
+    }
+    delete_task(transaction, issue_id, subscriber_id).await?;
+    Ok(ExecutionOutcome::TaskCompleted)
+}
+
+type PgTransaction = Transaction<'static, Postgres>;
+
+#[tracing::instrument(skip_all)]
+async fn dequeue_task(pool: &PgPool) -> Result<Option<(PgTransaction, Uuid, Uuid)>, sqlx::Error> {
+    let mut transaction = timed_begin(pool).await?;
+    // Other workers skip the row we locked instead of waiting on it
+    let r = sqlx::query!(
+        r#"
+        SELECT newsletter_issue_id, subscriber_id
+        FROM issue_delivery_queue
+        FOR UPDATE
+        SKIP LOCKED
+        LIMIT 1
+        "#,
+    )
+    .fetch_optional(&mut transaction)
+    .timed("worker.dequeue_task")
+    .await?;
+    if let Some(r) = r {
+        Ok(Some((transaction, r.newsletter_issue_id, r.subscriber_id)))
+    } else {
+        Ok(None)
+    }
+}
+
+#[tracing::instrument(skip_all)]
+async fn delete_task(
+    mut transaction: PgTransaction,
+    issue_id: Uuid,
+    subscriber_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        DELETE FROM issue_delivery_queue
+        WHERE
+            newsletter_issue_id = $1 AND
+            subscriber_id = $2
+        "#,
+        issue_id,
+        subscriber_id
+    )
+    .execute(&mut transaction)
+    .timed("worker.delete_task")
+    .await?;
+    transaction.commit().await?;
+    Ok(())
+}
+
+struct IssueContent {
+    title: String,
+    markdown: String,
+    tracking_enabled: bool,
+}
+
+#[tracing::instrument(skip_all)]
+async fn get_issue(pool: &PgPool, issue_id: Uuid) -> Result<IssueContent, sqlx::Error> {
+    sqlx::query_as!(
+        IssueContent,
+        r#"
+        SELECT title, markdown, tracking_enabled
+        FROM newsletter_issues
+        WHERE id = $1
+        "#,
+        issue_id
+    )
+    .fetch_one(pool)
+    .timed_one("worker.get_issue")
+    .await
+}
+
+struct Subscriber {
+    email: String,
+    name: String,
+    allow_tracking: bool,
+    unsubscribe_token: Option<String>,
+    suppressed: bool,
+}
+
+#[tracing::instrument(skip_all)]
+async fn get_subscriber(pool: &PgPool, subscriber_id: Uuid) -> Result<Subscriber, sqlx::Error> {
+    sqlx::query_as!(
+        Subscriber,
+        r#"
+        SELECT
+            email, name, allow_tracking, unsubscribe_token,
+            EXISTS (SELECT 1 FROM suppressions WHERE email = lower(subscriptions.email)) AS "suppressed!"
+        FROM subscriptions
+        WHERE id = $1
+        "#,
+        subscriber_id
+    )
+    .fetch_one(pool)
+    .timed_one("worker.get_subscriber")
+    .await
+}
+
+// Subscribers from before unsubscribe tokens get theirs with their next email
+#[tracing::instrument(skip_all)]
+async fn assign_unsubscribe_token(pool: &PgPool, subscriber_id: Uuid) -> Result<String, sqlx::Error> {
+    let r = sqlx::query!(
+        r#"
+        UPDATE subscriptions SET unsubscribe_token = COALESCE(unsubscribe_token, $2)
+        WHERE id = $1
+        RETURNING unsubscribe_token AS "unsubscribe_token!"
+        "#,
+        subscriber_id,
+        new_token()
+    )
+    .fetch_one(pool)
+    .timed_one("worker.assign_unsubscribe_token")
+    .await?;
+    Ok(r.unsubscribe_token)
+}
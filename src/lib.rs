@@ -1,9 +1,24 @@
 // making modules available
 #![allow(clippy::toplevel_ref_arg)]
+// actix-web's `HttpResponse` is itself a `Future`, which trips this lint on every handler
+#![allow(clippy::async_yields_async)]
+pub mod build_info;
 pub mod configuration;
 pub mod routes;
 pub mod startup;
 pub mod telemetry;
 pub mod controller;
+pub mod cors;
 pub mod models;
 pub mod constants;
+pub mod email_client;
+pub mod issue_delivery_worker;
+pub mod migrations;
+pub mod monitoring;
+pub mod rate_limit;
+pub mod request_id;
+pub mod security_headers;
+pub mod shutdown;
+pub mod templating;
+pub mod tls;
+pub mod tracking;
@@ -1,51 +1,194 @@
-use rust2prod_api::startup::{run};
-use rust2prod_api::configuration::get_configuration;
-use rust2prod_api::telemetry::{get_subscriber, init_subscriber};
-// use sqlx::postgres::PgPoolOptions;
-use sqlx::postgres::PgPool;
-use std::net::TcpListener;
-use std::env;
+use rust2prod_api::build_info::build_info;
+use rust2prod_api::configuration::{effective_configuration, get_configuration, Settings};
+use rust2prod_api::issue_delivery_worker::{run_worker_until_stopped, DeliveryContext};
+use rust2prod_api::migrations::{migration_status, pending_migrations, run_migrations};
+use rust2prod_api::models::admin::Admin;
+use rust2prod_api::monitoring::prometheus;
+use rust2prod_api::shutdown::{shutdown_on_signal, Shutdown};
+use rust2prod_api::startup::{get_connection_pool, run_metrics, wait_for_database, Application};
+use rust2prod_api::telemetry::{get_subscriber, get_tracer_provider, init_subscriber, set_pii_policy, Redactor};
 use dotenv::dotenv; // ability get variables from .env file
+use opentelemetry::trace::TracerProvider;
+use std::net::TcpListener;
 
+const USAGE: &str = "\
+Usage: rust2prod_api [COMMAND]
+
+Commands:
+  serve                     Serve the API and deliver newsletters (the default)
+  worker                    Only deliver newsletters, serving /metrics on
+                            application.metrics_port if it is set
+  migrate [up] [--dry-run]  Apply pending migrations, or only list them
+  migrate status            List every migration and whether it was applied
+  create-admin --email <EMAIL>
+                            Make a user an admin and print their new API token
+  config print              Print the configuration and where each value is from
+  check                     Check the configuration and that the database is
+                            reachable, exiting with 1 if either is not";
 
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
-    // use heroku env vars
     dotenv().ok();
 
-    // use port provided by heroku
-    let port: u16 = env::var("PORT")
-        .unwrap_or_else(|_| "8000".to_string())
-        .parse()
-        .expect("PORT must be a number");
-
-    // use full name as PgPoolConnect not quite working (maybe ssl? idk) need to move on but can maybe tie to dbname or smth
-    let db_name: String = env::var("DATABASE_URL")
-        .expect("Fail");
-        
-    for (n,v) in env::vars_os() {
-        println!("{}: {}", n.into_string().unwrap(), v.into_string().unwrap());
-    }
-    
-    let subscriber = get_subscriber("rust2prod_api".into(), "info".into(), std::io::stdout);
-    init_subscriber(subscriber);
-    // old logger env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    // These report on the configuration, so they must cope with a broken one
+    match args.as_slice() {
+        ["config", "print"] => return config_print(),
+        ["check"] => return check().await,
+        _ => {}
+    }
+
     // Panic if we can't read configuration
     let configuration = get_configuration().expect("Failed to read configuration.");
-    // No longer async, given that we don't actually try to connect!
-    let connection_pool = PgPool::connect(&db_name)
-        .await
-        .expect("Failed to connect");
-    // let connection_pool = PgPoolOptions::new()
-    //     .connect_timeout(std::time::Duration::from_secs(10))
-    //     .connect_lazy_with(configuration.database.with_db());
-    // Bubble up the io::Error if we failed to bind the address
-    // Otherwise call .await on our Server
-    // We have removed the hard-coded `8000` - it's now coming from our settings! buuuuut hard coded back in for heroku
-    let address = format!(
-        "{}:{}",
-        configuration.application.host, port
+    // Read first, so the logs know which values to scrub
+    let redactor = Redactor::new(configuration.secrets());
+    set_pii_policy(configuration.telemetry.pii);
+    let tracer_provider =
+        get_tracer_provider(&configuration.telemetry).expect("Failed to set up the trace exporter.");
+    let tracer = tracer_provider.tracer("rust2prod_api");
+    opentelemetry::global::set_tracer_provider(tracer_provider);
+    let (subscriber, log_level) =
+        get_subscriber("rust2prod_api".into(), "info".into(), redactor, tracer, std::io::stdout);
+    init_subscriber(subscriber, log_level);
+    configuration.validate().expect("Invalid configuration.");
+    let build = build_info();
+    tracing::info!(
+        build_timestamp = build.build_timestamp,
+        rustc = build.rustc,
+        features = %build.features.join(","),
+        environment = configuration.environment.as_str(),
+        "Starting rust2prod_api"
     );
-    let listener = TcpListener::bind(address)?;
-    run(listener, connection_pool)?.await
-}
\ No newline at end of file
+
+    let outcome = match args.as_slice() {
+        [] | ["serve"] => serve(configuration).await,
+        ["worker"] => worker(configuration).await,
+        ["migrate"] | ["migrate", "up"] => migrate(configuration, false).await,
+        ["migrate", "--dry-run"] | ["migrate", "up", "--dry-run"] => migrate(configuration, true).await,
+        ["migrate", "status"] => status(configuration).await,
+        ["create-admin", "--email", email] if email.contains('@') => create_admin(configuration, email).await,
+        _ => {
+            eprintln!("{}", USAGE);
+            std::process::exit(2);
+        }
+    };
+    // Export the spans still batched up
+    opentelemetry::global::shutdown_tracer_provider();
+    outcome
+}
+
+async fn serve(configuration: Settings) -> std::io::Result<()> {
+    // Issues are delivered by a worker running next to the API, on the same
+    // pool so the process stays within `database.max_connections`
+    let db_pool = get_connection_pool(&configuration.database);
+    let delivery_context =
+        DeliveryContext::build(&configuration, db_pool.clone()).expect("Failed to load the newsletter layout.");
+    let application = Application::build_with_pool(configuration, db_pool).await?;
+    let shutdown = application.shutdown();
+    tokio::spawn(shutdown_on_signal(shutdown.clone()));
+    application
+        .run_until_stopped_alongside(run_worker_until_stopped(delivery_context, shutdown))
+        .await
+}
+
+async fn worker(configuration: Settings) -> std::io::Result<()> {
+    let db_pool = get_connection_pool(&configuration.database);
+    let delivery_context =
+        DeliveryContext::build(&configuration, db_pool.clone()).expect("Failed to load the newsletter layout.");
+    wait_for_database(&delivery_context.db_pool, &configuration.database.startup_retry)
+        .await
+        .map_err(std::io::Error::other)?;
+    if let Some(port) = configuration.application.metrics_port {
+        prometheus();
+        let listener = TcpListener::bind(format!("{}:{}", configuration.application.host, port))?;
+        tokio::spawn(run_metrics(listener, delivery_context.db_pool.clone())?);
+    }
+    let shutdown = Shutdown::new();
+    tokio::spawn(shutdown_on_signal(shutdown.clone()));
+    run_worker_until_stopped(delivery_context, shutdown).await;
+    db_pool.close().await;
+    Ok(())
+}
+
+async fn migrate(configuration: Settings, dry_run: bool) -> std::io::Result<()> {
+    let pool = get_connection_pool(&configuration.database);
+    let migrations = if dry_run {
+        pending_migrations(&pool).await
+    } else {
+        run_migrations(&pool).await
+    }
+    .map_err(std::io::Error::other)?;
+    if migrations.is_empty() {
+        println!("The database is up to date.");
+    }
+    for migration in migrations {
+        let verb = if dry_run { "Would apply" } else { "Applied" };
+        println!("{} {} {}", verb, migration.version, migration.description);
+    }
+    Ok(())
+}
+
+async fn status(configuration: Settings) -> std::io::Result<()> {
+    let pool = get_connection_pool(&configuration.database);
+    let migrations = migration_status(&pool)
+        .await
+        .map_err(std::io::Error::other)?;
+    for migration in migrations {
+        println!(
+            "{:<8} {} {}",
+            migration.state.as_str(),
+            migration.version,
+            migration.description
+        );
+    }
+    Ok(())
+}
+
+async fn create_admin(configuration: Settings, email: &str) -> std::io::Result<()> {
+    let pool = get_connection_pool(&configuration.database);
+    let token = Admin::create(&pool, email)
+        .await
+        .map_err(std::io::Error::other)?;
+    println!("{} is an admin. Their API token, which is not shown again, is:", email);
+    println!("{}", token);
+    Ok(())
+}
+
+fn config_print() -> std::io::Result<()> {
+    let values = effective_configuration().map_err(std::io::Error::other)?;
+    let width = values.iter().map(|v| v.key.len() + v.value.len() + 3).max().unwrap_or(0);
+    for value in values {
+        let assignment = format!("{} = {}", value.key, value.value);
+        println!("{:<width$}  # {}", assignment, value.source, width = width);
+    }
+    Ok(())
+}
+
+async fn check() -> std::io::Result<()> {
+    let configuration = match get_configuration() {
+        Ok(configuration) => configuration,
+        Err(e) => {
+            println!("FAILED configuration: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let mut healthy = true;
+    match configuration.validate() {
+        Ok(()) => println!("ok     configuration ({})", configuration.environment.as_str()),
+        Err(e) => {
+            println!("FAILED {}", e);
+            healthy = false;
+        }
+    }
+    // A single attempt: a deployment check should not sit through the startup retries
+    let pool = get_connection_pool(&configuration.database);
+    match pool.acquire().await {
+        Ok(_) => println!("ok     database"),
+        Err(e) => {
+            println!("FAILED database: {}", e);
+            healthy = false;
+        }
+    }
+    std::process::exit(if healthy { 0 } else { 1 });
+}
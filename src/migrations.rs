@@ -0,0 +1,131 @@
+use sqlx::migrate::{Migrate, MigrateError, Migrator};
+use sqlx::{Executor, PgConnection, PgPool};
+
+/// The migrations in `migrations/`, compiled into the binary so it can
+/// migrate a database without the files or `sqlx-cli` being around.
+pub static MIGRATOR: Migrator = sqlx::migrate!("./migrations");
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MigrationState {
+    Applied,
+    Pending,
+    // Applied, but the file has changed since
+    Modified,
+}
+
+impl MigrationState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MigrationState::Applied => "applied",
+            MigrationState::Pending => "pending",
+            MigrationState::Modified => "modified",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct MigrationStatus {
+    pub version: i64,
+    pub description: String,
+    pub state: MigrationState,
+}
+
+/// Every embedded migration and whether it has been applied, oldest first.
+/// Only reads from the database, so it is safe to run against production.
+pub async fn migration_status(pool: &PgPool) -> Result<Vec<MigrationStatus>, MigrateError> {
+    let mut connection = pool.acquire().await?;
+    status_on(&mut connection).await
+}
+
+pub async fn pending_migrations(pool: &PgPool) -> Result<Vec<MigrationStatus>, MigrateError> {
+    let mut status = migration_status(pool).await?;
+    status.retain(|migration| migration.state == MigrationState::Pending);
+    Ok(status)
+}
+
+/// Apply every pending migration, returning the ones that were.
+///
+/// The whole run holds a Postgres advisory lock (the one `sqlx-cli` takes
+/// too), so instances migrating at the same time take turns, and the ones
+/// coming second find nothing left to do. `database.statement_timeout` is
+/// lifted meanwhile: it is meant for requests, and cancelling a migration,
+/// or the wait for the lock behind another instance's, would leave a
+/// half-migrated database.
+#[tracing::instrument(name = "Running database migrations", skip(pool))]
+pub async fn run_migrations(pool: &PgPool) -> Result<Vec<MigrationStatus>, MigrateError> {
+    let mut connection = pool.acquire().await?;
+    connection.execute("SET statement_timeout = 0").await?;
+    let applied = apply_locked(&mut connection).await;
+    // Back to the pool's own before the connection is returned to it
+    connection.execute("RESET statement_timeout").await?;
+    let applied = applied?;
+    for migration in &applied {
+        tracing::info!(version = migration.version, description = %migration.description, "Applied migration");
+    }
+    Ok(applied)
+}
+
+async fn apply_locked(connection: &mut PgConnection) -> Result<Vec<MigrationStatus>, MigrateError> {
+    connection.lock().await?;
+    let applied = apply_pending(connection).await;
+    // Session locks outlive the checkout, so release it even on failure
+    connection.unlock().await?;
+    applied
+}
+
+// What `Migrator::run` does, but on the connection holding the lock
+async fn apply_pending(connection: &mut PgConnection) -> Result<Vec<MigrationStatus>, MigrateError> {
+    connection.ensure_migrations_table().await?;
+    if let Some(version) = connection.dirty_version().await? {
+        return Err(MigrateError::Dirty(version));
+    }
+    let status = status_on(connection).await?;
+    if let Some(modified) = status.iter().find(|m| m.state == MigrationState::Modified) {
+        return Err(MigrateError::VersionMismatch(modified.version));
+    }
+    let mut applied = Vec::new();
+    for migration in status.into_iter().filter(|m| m.state == MigrationState::Pending) {
+        let source = MIGRATOR
+            .iter()
+            .find(|m| m.version == migration.version && !m.migration_type.is_down_migration())
+            .expect("The status only lists embedded migrations");
+        connection.apply(source).await?;
+        applied.push(MigrationStatus {
+            state: MigrationState::Applied,
+            ..migration
+        });
+    }
+    Ok(applied)
+}
+
+async fn status_on(connection: &mut PgConnection) -> Result<Vec<MigrationStatus>, MigrateError> {
+    let tracked = sqlx::query!(
+        r#"SELECT to_regclass('_sqlx_migrations') IS NOT NULL AS "tracked!""#
+    )
+    .fetch_one(&mut *connection)
+    .await?
+    .tracked;
+    let applied = if tracked {
+        connection.list_applied_migrations().await?
+    } else {
+        // Nothing was ever applied, and checking should not create the table
+        Vec::new()
+    };
+    Ok(MIGRATOR
+        .iter()
+        .filter(|migration| !migration.migration_type.is_down_migration())
+        .map(|migration| {
+            let state = match applied.iter().find(|a| a.version == migration.version) {
+                Some(a) if a.checksum == migration.checksum => MigrationState::Applied,
+                Some(_) => MigrationState::Modified,
+                None => MigrationState::Pending,
+            };
+            MigrationStatus {
+                version: migration.version,
+                description: migration.description.to_string(),
+                state,
+            }
+        })
+        .collect())
+}
+
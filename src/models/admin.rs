@@ -0,0 +1,84 @@
+use chrono::Utc;
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::monitoring::{timed_begin, TimedQuery};
+use crate::telemetry::Redacted;
+use crate::tracking::new_token;
+
+/// A user allowed to use the admin API.
+pub struct Admin;
+
+impl Admin {
+    /// Make the user with this email an admin, creating the user if there is
+    /// none, and return a new API token for them. An admin's old token stops
+    /// working, so this doubles as a way to rotate it.
+    #[tracing::instrument(name = "Creating an admin", skip(db_pool, email), fields(email = %Redacted(email)))]
+    pub async fn create(db_pool: &PgPool, email: &str) -> Result<String, sqlx::Error> {
+        let token = new_token();
+        let mut transaction = timed_begin(db_pool).await?;
+        // The no-op update makes `RETURNING` yield the existing row too
+        let user = sqlx::query!(
+            r#"
+        INSERT INTO users (id, name, email, created_at)
+        VALUES ($1, $2, $3, $4)
+        ON CONFLICT (email) DO UPDATE SET email = EXCLUDED.email
+        RETURNING id
+        "#,
+            Uuid::new_v4().to_string(),
+            // New admins are named after their email
+            email,
+            email,
+            Utc::now(),
+        )
+        .fetch_one(&mut transaction)
+        .timed_one("admin.upsert_user")
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        })?;
+        sqlx::query!(
+            r#"
+        INSERT INTO admins (user_id, token_hash, created_at)
+        VALUES ($1, $2, $3)
+        ON CONFLICT (user_id) DO UPDATE SET token_hash = EXCLUDED.token_hash
+        "#,
+            user.id,
+            hash_token(&token),
+            Utc::now(),
+        )
+        .execute(&mut transaction)
+        .timed("admin.upsert_token")
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        })?;
+        transaction.commit().await?;
+        Ok(token)
+    }
+
+    /// The id of the admin an API token belongs to, if it belongs to one.
+    #[tracing::instrument(name = "Authenticating an admin", skip(db_pool, token))]
+    pub async fn authenticate(db_pool: &PgPool, token: &str) -> Result<Option<String>, sqlx::Error> {
+        let admin = sqlx::query!(
+            r#"SELECT user_id FROM admins WHERE token_hash = $1"#,
+            hash_token(token),
+        )
+        .fetch_optional(db_pool)
+        .timed("admin.authenticate")
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        })?;
+        Ok(admin.map(|admin| admin.user_id))
+    }
+}
+
+// Tokens are as good as passwords, so only their hash is stored
+fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
@@ -0,0 +1,251 @@
+use chrono::Utc;
+use serde::Serialize;
+use sqlx::PgPool;
+use uuid::Uuid;
+use crate::monitoring::{timed_begin, TimedQuery};
+use crate::tracking::TrackedLink;
+
+/// What happened to a single email handed to the email provider.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DeliveryStatus {
+    // Recorded, but not handed to the email provider yet
+    Pending,
+    Sent,
+    Failed,
+    // Reported back by the email provider
+    Delivered,
+    Bounced,
+    Complained,
+}
+
+impl DeliveryStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeliveryStatus::Pending => "pending",
+            DeliveryStatus::Sent => "sent",
+            DeliveryStatus::Failed => "failed",
+            DeliveryStatus::Delivered => "delivered",
+            DeliveryStatus::Bounced => "bounced",
+            DeliveryStatus::Complained => "complained",
+        }
+    }
+}
+
+pub struct IssueDelivery;
+
+impl IssueDelivery {
+    /// Record a delivery before it is sent, along with its tracked links,
+    /// so that the tracker knows about them by the time the email arrives.
+    pub async fn insert_pending(
+        db_pool: &PgPool,
+        delivery_id: Uuid,
+        issue_id: Uuid,
+        subscriber_id: Uuid,
+        tracking_token: Option<&str>,
+        links: &[TrackedLink],
+    ) -> Result<(), sqlx::Error> {
+        let mut transaction = timed_begin(db_pool).await?;
+        sqlx::query!(
+            r#"
+        INSERT INTO issue_deliveries (id, newsletter_issue_id, subscriber_id, status, tracking_token, created_at)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        "#,
+            delivery_id,
+            issue_id,
+            subscriber_id,
+            DeliveryStatus::Pending.as_str(),
+            tracking_token,
+            Utc::now()
+        )
+        .execute(&mut transaction)
+        .timed("issue_delivery.insert_pending")
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        })?;
+        for link in links {
+            sqlx::query!(
+                r#"
+        INSERT INTO tracked_links (token, delivery_id, url)
+        VALUES ($1, $2, $3)
+        "#,
+                link.token,
+                delivery_id,
+                link.url
+            )
+            .execute(&mut transaction)
+            .timed("issue_delivery.insert_tracked_link")
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to execute query: {:?}", e);
+                e
+            })?;
+        }
+        transaction.commit().await
+    }
+
+    pub async fn set_status(
+        db_pool: &PgPool,
+        delivery_id: Uuid,
+        status: DeliveryStatus,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            r#"
+        UPDATE issue_deliveries SET status = $2
+        WHERE id = $1
+        "#,
+            delivery_id,
+            status.as_str()
+        )
+        .execute(db_pool)
+        .timed("issue_delivery.set_status")
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        })?;
+        Ok(())
+    }
+
+    /// Record an open of the delivery with this tracking token.
+    /// Returns `false` if the token is unknown.
+    pub async fn record_open(db_pool: &PgPool, tracking_token: &str) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query!(
+            r#"
+        INSERT INTO tracking_events (id, delivery_id, kind, occurred_at)
+        SELECT $1, id, 'open', $2 FROM issue_deliveries
+        WHERE tracking_token = $3
+        "#,
+            Uuid::new_v4(),
+            Utc::now(),
+            tracking_token
+        )
+        .execute(db_pool)
+        .timed("issue_delivery.record_open")
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        })?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    /// Record a click on the tracked link with this token, returning the
+    /// URL it stands for, or `None` if the token is unknown.
+    pub async fn record_click(db_pool: &PgPool, link_token: &str) -> Result<Option<String>, sqlx::Error> {
+        let clicked = sqlx::query!(
+            r#"
+        WITH link AS (
+            SELECT token, delivery_id, url FROM tracked_links
+            WHERE token = $3
+        ), event AS (
+            INSERT INTO tracking_events (id, delivery_id, kind, link_token, occurred_at)
+            SELECT $1, delivery_id, 'click', token, $2 FROM link
+        )
+        SELECT url FROM link
+        "#,
+            Uuid::new_v4(),
+            Utc::now(),
+            link_token
+        )
+        .fetch_optional(db_pool)
+        .timed("issue_delivery.record_click")
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        })?;
+        Ok(clicked.map(|c| c.url))
+    }
+}
+
+#[derive(Serialize)]
+pub struct LinkStats {
+    pub url: String,
+    pub clicks: i64,
+}
+
+/// How an issue performed.
+///
+/// `sent` counts emails the provider accepted, `delivered` those it has
+/// since confirmed. A click also counts as an open, since many email
+/// clients block the tracking pixel.
+#[derive(Serialize)]
+pub struct IssueStats {
+    pub sent: i64,
+    pub delivered: i64,
+    pub failed: i64,
+    pub unique_opens: i64,
+    pub unique_clicks: i64,
+    pub links: Vec<LinkStats>,
+}
+
+impl IssueStats {
+    pub async fn for_issue(db_pool: &PgPool, issue_id: Uuid) -> Result<IssueStats, sqlx::Error> {
+        let deliveries = sqlx::query!(
+            r#"
+        SELECT
+            COUNT(*) FILTER (WHERE status NOT IN ('pending', 'failed')) AS "sent!",
+            COUNT(*) FILTER (WHERE status = 'delivered') AS "delivered!",
+            COUNT(*) FILTER (WHERE status = 'failed') AS "failed!"
+        FROM issue_deliveries
+        WHERE newsletter_issue_id = $1
+        "#,
+            issue_id
+        )
+        .fetch_one(db_pool)
+        .timed_one("issue_delivery.count_deliveries")
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        })?;
+        let engagement = sqlx::query!(
+            r#"
+        SELECT
+            COUNT(DISTINCT e.delivery_id) AS "unique_opens!",
+            COUNT(DISTINCT e.delivery_id) FILTER (WHERE e.kind = 'click') AS "unique_clicks!"
+        FROM tracking_events e
+        JOIN issue_deliveries d ON d.id = e.delivery_id
+        WHERE d.newsletter_issue_id = $1
+        "#,
+            issue_id
+        )
+        .fetch_one(db_pool)
+        .timed_one("issue_delivery.count_engagement")
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        })?;
+        let links = sqlx::query_as!(
+            LinkStats,
+            r#"
+        SELECT l.url, COUNT(e.id) AS "clicks!"
+        FROM tracked_links l
+        JOIN issue_deliveries d ON d.id = l.delivery_id
+        LEFT JOIN tracking_events e ON e.link_token = l.token
+        WHERE d.newsletter_issue_id = $1
+        GROUP BY l.url
+        ORDER BY 2 DESC, l.url
+        "#,
+            issue_id
+        )
+        .fetch_all(db_pool)
+        .timed("issue_delivery.count_link_clicks")
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        })?;
+        Ok(IssueStats {
+            sent: deliveries.sent,
+            delivered: deliveries.delivered,
+            failed: deliveries.failed,
+            unique_opens: engagement.unique_opens,
+            unique_clicks: engagement.unique_clicks,
+            links,
+        })
+    }
+}
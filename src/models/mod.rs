@@ -1 +1,5 @@
+pub mod admin;
+pub mod issue_delivery;
+pub mod newsletter_issue;
+pub mod suppression;
 pub mod user;
\ No newline at end of file
@@ -0,0 +1,374 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::PgPool;
+use uuid::Uuid;
+use crate::monitoring::{timed_begin, TimedQuery};
+use crate::routes::BodyData;
+
+/// Where an issue is in its lifecycle.
+///
+/// Drafts and scheduled issues can still be edited or cancelled. Once the
+/// delivery worker moves an issue to `Sending` it can only go on to `Sent`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IssueState {
+    Draft,
+    Scheduled,
+    Sending,
+    Sent,
+    Cancelled,
+}
+
+impl IssueState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            IssueState::Draft => "draft",
+            IssueState::Scheduled => "scheduled",
+            IssueState::Sending => "sending",
+            IssueState::Sent => "sent",
+            IssueState::Cancelled => "cancelled",
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct NewsletterIssue {
+    pub id: Uuid,
+    pub title: String,
+    pub markdown: String,
+    pub state: String,
+    pub slug: String,
+    pub private: bool,
+    pub tracking_enabled: bool,
+    pub scheduled_for: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    pub sent_at: Option<DateTime<Utc>>,
+}
+
+impl NewsletterIssue {
+    pub async fn insert_draft(db_pool: &PgPool, body: &BodyData) -> Result<NewsletterIssue, sqlx::Error> {
+        let issue_id = Uuid::new_v4();
+        let now = Utc::now();
+        sqlx::query_as!(
+            NewsletterIssue,
+            r#"
+        INSERT INTO newsletter_issues (id, title, markdown, state, slug, private, tracking_enabled, created_at, updated_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $8)
+        RETURNING *
+        "#,
+            issue_id,
+            body.title,
+            body.markdown,
+            IssueState::Draft.as_str(),
+            slug_for(&body.title, issue_id),
+            body.private.unwrap_or(false),
+            body.tracking_enabled.unwrap_or(true),
+            now
+        )
+        .fetch_one(db_pool)
+        .timed_one("newsletter_issue.insert_draft")
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        })
+    }
+
+    /// Store an issue that skips the draft stage and queue it for every subscriber.
+    pub async fn insert_for_delivery(
+        db_pool: &PgPool,
+        body: &BodyData,
+    ) -> Result<NewsletterIssue, sqlx::Error> {
+        let mut transaction = timed_begin(db_pool).await?;
+        let issue_id = Uuid::new_v4();
+        let now = Utc::now();
+        let issue = sqlx::query_as!(
+            NewsletterIssue,
+            r#"
+        INSERT INTO newsletter_issues (id, title, markdown, state, slug, private, tracking_enabled, created_at, updated_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $8)
+        RETURNING *
+        "#,
+            issue_id,
+            body.title,
+            body.markdown,
+            IssueState::Sending.as_str(),
+            slug_for(&body.title, issue_id),
+            body.private.unwrap_or(false),
+            body.tracking_enabled.unwrap_or(true),
+            now
+        )
+        .fetch_one(&mut transaction)
+        .timed_one("newsletter_issue.insert_for_delivery")
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        })?;
+        sqlx::query!(
+            r#"
+        INSERT INTO issue_delivery_queue (newsletter_issue_id, subscriber_id)
+        SELECT $1, id FROM subscriptions
+        "#,
+            issue.id
+        )
+        .execute(&mut transaction)
+        .timed("newsletter_issue.queue_deliveries")
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        })?;
+        transaction.commit().await?;
+        Ok(issue)
+    }
+
+    pub async fn find_all(db_pool: &PgPool) -> Result<Vec<NewsletterIssue>, sqlx::Error> {
+        sqlx::query_as!(
+            NewsletterIssue,
+            r#"
+        SELECT * FROM newsletter_issues
+        ORDER BY created_at DESC
+        "#
+        )
+        .fetch_all(db_pool)
+        .timed("newsletter_issue.find_all")
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        })
+    }
+
+    pub async fn get_by_id(db_pool: &PgPool, issue_id: Uuid) -> Result<NewsletterIssue, sqlx::Error> {
+        sqlx::query_as!(
+            NewsletterIssue,
+            r#"
+        SELECT * FROM newsletter_issues
+        WHERE id = $1
+        "#,
+            issue_id
+        )
+        .fetch_one(db_pool)
+        .timed_one("newsletter_issue.get_by_id")
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        })
+    }
+
+    /// Edit the content of an issue that has not started sending yet.
+    /// Returns `None` if there is no such issue, or it is past that point.
+    pub async fn update_unsent(
+        db_pool: &PgPool,
+        issue_id: Uuid,
+        body: &BodyData,
+    ) -> Result<Option<NewsletterIssue>, sqlx::Error> {
+        sqlx::query_as!(
+            NewsletterIssue,
+            r#"
+        UPDATE newsletter_issues
+        SET
+            title = $2, markdown = $3, slug = $4,
+            private = COALESCE($5, private),
+            tracking_enabled = COALESCE($6, tracking_enabled),
+            updated_at = $7
+        WHERE id = $1 AND state IN ('draft', 'scheduled')
+        RETURNING *
+        "#,
+            issue_id,
+            body.title,
+            body.markdown,
+            slug_for(&body.title, issue_id),
+            body.private,
+            body.tracking_enabled,
+            Utc::now()
+        )
+        .fetch_optional(db_pool)
+        .timed("newsletter_issue.update_unsent")
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        })
+    }
+
+    /// Schedule (or reschedule) an issue that has not started sending yet.
+    pub async fn schedule(
+        db_pool: &PgPool,
+        issue_id: Uuid,
+        scheduled_for: DateTime<Utc>,
+    ) -> Result<Option<NewsletterIssue>, sqlx::Error> {
+        sqlx::query_as!(
+            NewsletterIssue,
+            r#"
+        UPDATE newsletter_issues SET state = $2, scheduled_for = $3, updated_at = $4
+        WHERE id = $1 AND state IN ('draft', 'scheduled')
+        RETURNING *
+        "#,
+            issue_id,
+            IssueState::Scheduled.as_str(),
+            scheduled_for,
+            Utc::now()
+        )
+        .fetch_optional(db_pool)
+        .timed("newsletter_issue.schedule")
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        })
+    }
+
+    /// Cancel an issue that has not started sending yet.
+    ///
+    /// The worker promotes issues with a conditional `UPDATE` too, so whichever
+    /// of the two gets the row lock first wins and the other matches nothing.
+    pub async fn cancel(db_pool: &PgPool, issue_id: Uuid) -> Result<Option<NewsletterIssue>, sqlx::Error> {
+        sqlx::query_as!(
+            NewsletterIssue,
+            r#"
+        UPDATE newsletter_issues SET state = $2, updated_at = $3
+        WHERE id = $1 AND state IN ('draft', 'scheduled')
+        RETURNING *
+        "#,
+            issue_id,
+            IssueState::Cancelled.as_str(),
+            Utc::now()
+        )
+        .fetch_optional(db_pool)
+        .timed("newsletter_issue.cancel")
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        })
+    }
+
+    /// Hide an issue from (or show it in) the public archive and feeds.
+    /// Unlike its content, this can change after the issue was sent.
+    pub async fn set_private(
+        db_pool: &PgPool,
+        issue_id: Uuid,
+        private: bool,
+    ) -> Result<Option<NewsletterIssue>, sqlx::Error> {
+        sqlx::query_as!(
+            NewsletterIssue,
+            r#"
+        UPDATE newsletter_issues SET private = $2, updated_at = $3
+        WHERE id = $1
+        RETURNING *
+        "#,
+            issue_id,
+            private,
+            Utc::now()
+        )
+        .fetch_optional(db_pool)
+        .timed("newsletter_issue.set_private")
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        })
+    }
+
+    /// The most recent sent, public issues, newest first.
+    pub async fn find_archived(db_pool: &PgPool, limit: i64) -> Result<Vec<NewsletterIssue>, sqlx::Error> {
+        sqlx::query_as!(
+            NewsletterIssue,
+            r#"
+        SELECT * FROM newsletter_issues
+        WHERE state = $1 AND NOT private
+        ORDER BY sent_at DESC
+        LIMIT $2
+        "#,
+            IssueState::Sent.as_str(),
+            limit
+        )
+        .fetch_all(db_pool)
+        .timed("newsletter_issue.find_archived")
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        })
+    }
+
+    pub async fn get_archived_by_slug(db_pool: &PgPool, slug: &str) -> Result<NewsletterIssue, sqlx::Error> {
+        sqlx::query_as!(
+            NewsletterIssue,
+            r#"
+        SELECT * FROM newsletter_issues
+        WHERE slug = $1 AND state = $2 AND NOT private
+        "#,
+            slug,
+            IssueState::Sent.as_str()
+        )
+        .fetch_one(db_pool)
+        .timed_one("newsletter_issue.get_archived_by_slug")
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        })
+    }
+
+    /// When the archive last changed: an issue was sent, or one that was
+    /// sent became private or public again.
+    pub async fn archive_last_modified(db_pool: &PgPool) -> Result<Option<DateTime<Utc>>, sqlx::Error> {
+        let last_modified = sqlx::query!(
+            r#"
+        SELECT max(updated_at) AS last_modified FROM newsletter_issues
+        WHERE state = $1
+        "#,
+            IssueState::Sent.as_str()
+        )
+        .fetch_one(db_pool)
+        .timed_one("newsletter_issue.archive_last_modified")
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        })?;
+        Ok(last_modified.last_modified)
+    }
+
+    /// Delete a draft. Returns `false` if there is no draft with that id.
+    pub async fn delete_draft(db_pool: &PgPool, issue_id: Uuid) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query!(
+            r#"
+        DELETE FROM newsletter_issues
+        WHERE id = $1 AND state = $2
+        "#,
+            issue_id,
+            IssueState::Draft.as_str()
+        )
+        .execute(db_pool)
+        .timed("newsletter_issue.delete_draft")
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        })?;
+        Ok(result.rows_affected() == 1)
+    }
+}
+
+/// A URL-friendly version of `title`, e.g. `hello-world-1a2b3c4d` for
+/// "Hello, World!". The id suffix keeps slugs unique across issues.
+pub fn slug_for(title: &str, issue_id: Uuid) -> String {
+    let mut slug = String::new();
+    for c in title.chars() {
+        if c.is_ascii_alphanumeric() {
+            slug.push(c.to_ascii_lowercase());
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    if !slug.is_empty() && !slug.ends_with('-') {
+        slug.push('-');
+    }
+    slug.push_str(&issue_id.to_simple().to_string()[..8]);
+    slug
+}
@@ -0,0 +1,148 @@
+use chrono::Utc;
+use sqlx::PgPool;
+use crate::monitoring::{timed_begin, TimedQuery};
+
+/// Why an address ended up on the suppression list.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SuppressionReason {
+    HardBounce,
+    // Too many soft bounces in a row
+    SoftBounce,
+    Complaint,
+    // Through the link in every newsletter
+    Unsubscribed,
+}
+
+impl SuppressionReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SuppressionReason::HardBounce => "hard_bounce",
+            SuppressionReason::SoftBounce => "soft_bounce",
+            SuppressionReason::Complaint => "complaint",
+            SuppressionReason::Unsubscribed => "unsubscribed",
+        }
+    }
+
+    // The status the matching subscription is moved to
+    fn subscription_status(&self) -> &'static str {
+        match self {
+            SuppressionReason::HardBounce | SuppressionReason::SoftBounce => "bounced",
+            SuppressionReason::Complaint => "complained",
+            SuppressionReason::Unsubscribed => "unsubscribed",
+        }
+    }
+}
+
+pub struct Suppression;
+
+impl Suppression {
+    pub async fn is_suppressed(db_pool: &PgPool, email: &str) -> Result<bool, sqlx::Error> {
+        let suppressed = sqlx::query!(
+            r#"
+        SELECT EXISTS (SELECT 1 FROM suppressions WHERE email = lower($1)) AS "suppressed!"
+        "#,
+            email
+        )
+        .fetch_one(db_pool)
+        .timed_one("suppression.is_suppressed")
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        })?;
+        Ok(suppressed.suppressed)
+    }
+
+    /// Add `email` to the suppression list and mark its subscription accordingly.
+    /// Suppressing an address twice keeps the original reason.
+    pub async fn suppress(
+        db_pool: &PgPool,
+        email: &str,
+        reason: SuppressionReason,
+    ) -> Result<(), sqlx::Error> {
+        let mut transaction = timed_begin(db_pool).await?;
+        sqlx::query!(
+            r#"
+        INSERT INTO suppressions (email, reason, created_at)
+        VALUES (lower($1), $2, $3)
+        ON CONFLICT (email) DO NOTHING
+        "#,
+            email,
+            reason.as_str(),
+            Utc::now()
+        )
+        .execute(&mut transaction)
+        .timed("suppression.suppress")
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        })?;
+        sqlx::query!(
+            r#"
+        UPDATE subscriptions SET status = $2
+        WHERE lower(email) = lower($1)
+        "#,
+            email,
+            reason.subscription_status()
+        )
+        .execute(&mut transaction)
+        .timed("suppression.mark_subscription")
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        })?;
+        transaction.commit().await
+    }
+
+    /// Count a soft bounce against `email`, suppressing it once `threshold`
+    /// soft bounces in a row have been seen. Returns whether it was suppressed.
+    pub async fn record_soft_bounce(
+        db_pool: &PgPool,
+        email: &str,
+        threshold: u32,
+    ) -> Result<bool, sqlx::Error> {
+        let bounces = sqlx::query!(
+            r#"
+        UPDATE subscriptions SET soft_bounce_count = soft_bounce_count + 1
+        WHERE lower(email) = lower($1)
+        RETURNING soft_bounce_count
+        "#,
+            email
+        )
+        .fetch_optional(db_pool)
+        .timed("suppression.record_soft_bounce")
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        })?;
+        match bounces {
+            Some(bounces) if bounces.soft_bounce_count as i64 >= threshold as i64 => {
+                Suppression::suppress(db_pool, email, SuppressionReason::SoftBounce).await?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// A successful delivery breaks a streak of soft bounces.
+    pub async fn reset_soft_bounces(db_pool: &PgPool, email: &str) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            r#"
+        UPDATE subscriptions SET soft_bounce_count = 0
+        WHERE lower(email) = lower($1)
+        "#,
+            email
+        )
+        .execute(db_pool)
+        .timed("suppression.reset_soft_bounces")
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        })?;
+        Ok(())
+    }
+}
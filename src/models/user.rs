@@ -3,6 +3,7 @@ use sqlx::{PgPool, query_as};
 use chrono::{Utc, DateTime};
 
 use crate::controller::user_controller::UserFormData;
+use crate::monitoring::TimedQuery;
 
 
 
@@ -38,6 +39,7 @@ impl User {
             "#
         )
         .fetch_all(db_pool)
+        .timed("user.find_all")
         .await
         .map_err(|e| {
             tracing::error!("Failed to execute query: {:?}", e);
@@ -59,6 +61,7 @@ impl User {
         user_id
         )
         .fetch_one(db_pool)
+        .timed_one("user.get_user_by_id")
         .await
         .map_err(|e| {
             tracing::error!("Failed to execute query: {:?}", e);
@@ -82,6 +85,7 @@ impl User {
         form.email
         )
         .fetch_one(db_pool)
+        .timed_one("user.update_user_by_id")
         .await
         .map_err(|e| {
             tracing::error!("Failed to execute query: {:?}", e);
@@ -98,6 +102,7 @@ impl User {
         user_id
         )
         .execute(db_pool)
+        .timed("user.delete_user_by_id")
         .await
         .map_err(|e| {
             tracing::error!("Failed to execute query: {:?}", e);
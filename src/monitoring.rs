@@ -0,0 +1,228 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::Method;
+use actix_web::{web, HttpMessage, HttpResponse};
+use metrics::{gauge, histogram, increment_counter};
+use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
+use sqlx::postgres::PgQueryResult;
+use sqlx::{PgPool, Postgres, Transaction};
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+use tracing::Instrument;
+
+// Histogram buckets, in seconds, for every duration we measure
+const DURATION_BUCKETS: [f64; 12] = [
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0,
+];
+
+// Stands in for the route of requests no route matched, so scanners
+// hitting random paths cannot blow up the number of series
+const UNMATCHED_ROUTE: &str = "unmatched";
+
+static PROMETHEUS: OnceLock<PrometheusHandle> = OnceLock::new();
+
+/// The process-wide Prometheus recorder, installed on first use. Metrics
+/// recorded before that are dropped, so the server installs it at startup.
+pub fn prometheus() -> &'static PrometheusHandle {
+    PROMETHEUS.get_or_init(|| {
+        PrometheusBuilder::new()
+            .set_buckets(&DURATION_BUCKETS)
+            .expect("The duration buckets are not empty")
+            .install_recorder()
+            .expect("Failed to install the Prometheus recorder")
+    })
+}
+
+/// `GET /metrics`, in the Prometheus text format. Gauges that are cheaper
+/// to read than to keep up to date are sampled here, once per scrape.
+pub async fn metrics(pool: web::Data<PgPool>) -> HttpResponse {
+    record_pool_metrics(&pool);
+    match queue_depth(&pool).await {
+        Ok(depth) => gauge!("newsletter_delivery_queue_depth", depth as f64),
+        Err(e) => tracing::error!("Failed to measure the delivery queue: {:?}", e),
+    }
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(prometheus().render())
+}
+
+fn record_pool_metrics(pool: &PgPool) {
+    let size = pool.size();
+    let idle = pool.num_idle() as u32;
+    gauge!("db_pool_connections", f64::from(size.saturating_sub(idle)), "state" => "active");
+    gauge!("db_pool_connections", f64::from(idle), "state" => "idle");
+}
+
+async fn queue_depth(pool: &PgPool) -> Result<i64, sqlx::Error> {
+    let r = sqlx::query!(r#"SELECT count(*) AS "depth!" FROM issue_delivery_queue"#)
+        .fetch_one(pool)
+        .timed_one("worker.count_queued_deliveries")
+        .await?;
+    Ok(r.depth)
+}
+
+/// Times sqlx queries: `.fetch_all(pool).timed("user.find_all").await`
+/// records how long the query took under that name, and how it went, in a
+/// span of its own (at debug level) and in the metrics, and adds it to the
+/// database time of the request being handled, if any.
+pub trait TimedQuery<T>: Future<Output = Result<T, sqlx::Error>> + Sized {
+    fn timed(self, name: &'static str) -> impl Future<Output = Result<T, sqlx::Error>>
+    where
+        T: QueryRows;
+
+    /// For `fetch_one`, whose records cannot say how many rows they are.
+    fn timed_one(self, name: &'static str) -> impl Future<Output = Result<T, sqlx::Error>>;
+}
+
+impl<T, F> TimedQuery<T> for F
+where
+    F: Future<Output = Result<T, sqlx::Error>>,
+{
+    async fn timed(self, name: &'static str) -> Result<T, sqlx::Error>
+    where
+        T: QueryRows,
+    {
+        time_query(name, self, QueryRows::rows).await
+    }
+
+    async fn timed_one(self, name: &'static str) -> Result<T, sqlx::Error> {
+        time_query(name, self, |_| 1).await
+    }
+}
+
+/// How many rows a query returned or changed.
+pub trait QueryRows {
+    fn rows(&self) -> u64;
+}
+
+impl<R> QueryRows for Vec<R> {
+    fn rows(&self) -> u64 {
+        self.len() as u64
+    }
+}
+
+impl<R> QueryRows for Option<R> {
+    fn rows(&self) -> u64 {
+        u64::from(self.is_some())
+    }
+}
+
+impl QueryRows for PgQueryResult {
+    fn rows(&self) -> u64 {
+        self.rows_affected()
+    }
+}
+
+async fn time_query<T>(
+    name: &'static str,
+    query: impl Future<Output = Result<T, sqlx::Error>>,
+    rows: impl FnOnce(&T) -> u64,
+) -> Result<T, sqlx::Error> {
+    let span = tracing::debug_span!(
+        "Database query",
+        db.query = name,
+        db.rows = tracing::field::Empty,
+        db.duration_us = tracing::field::Empty,
+    );
+    let started = Instant::now();
+    let outcome = query.instrument(span.clone()).await;
+    let elapsed = started.elapsed();
+    let status = match &outcome {
+        Ok(output) => {
+            span.record("db.rows", &rows(output));
+            "ok"
+        }
+        // An answer, if not the one hoped for
+        Err(sqlx::Error::RowNotFound) => {
+            span.record("db.rows", &0u64);
+            "not_found"
+        }
+        Err(_) => "error",
+    };
+    // Integers, as the JSON logs would turn a float into a string
+    span.record("db.duration_us", &(elapsed.as_micros() as u64));
+    histogram!(
+        "db_query_duration_seconds",
+        elapsed.as_secs_f64(),
+        "query" => name,
+        "outcome" => status
+    );
+    // Outside of requests, such as in the delivery worker, there is none
+    let _ = REQUEST_QUERIES.try_with(|queries| queries.add(elapsed));
+    outcome
+}
+
+/// Begins a transaction, recording how long that took, mostly waiting for
+/// a connection from the pool, in `db_pool_acquire_wait_seconds`. sqlx
+/// checks the connections of queries run on the pool itself out and in
+/// unseen, so a saturated pool shows up here, on the transactions.
+pub async fn timed_begin(pool: &PgPool) -> Result<Transaction<'static, Postgres>, sqlx::Error> {
+    let started = Instant::now();
+    let transaction = pool.begin().await?;
+    histogram!("db_pool_acquire_wait_seconds", started.elapsed().as_secs_f64());
+    Ok(transaction)
+}
+
+tokio::task_local! {
+    static REQUEST_QUERIES: Arc<QueryTotals>;
+}
+
+/// The queries made while handling a request, and how long they took.
+#[derive(Debug, Default)]
+pub struct QueryTotals {
+    count: AtomicU64,
+    micros: AtomicU64,
+}
+
+impl QueryTotals {
+    fn add(&self, elapsed: Duration) {
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.micros.fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    pub fn duration(&self) -> Duration {
+        Duration::from_micros(self.micros.load(Ordering::Relaxed))
+    }
+}
+
+/// Start adding up the queries made while handling the request, for its
+/// root span to report once it is done.
+pub fn track_queries(request: &ServiceRequest) -> Arc<QueryTotals> {
+    let totals = Arc::new(QueryTotals::default());
+    request.extensions_mut().insert(totals.clone());
+    totals
+}
+
+/// Handle the request, counting its queries towards `totals`.
+pub async fn with_query_totals<B>(
+    totals: Arc<QueryTotals>,
+    response: impl Future<Output = Result<ServiceResponse<B>, actix_web::Error>>,
+) -> Result<ServiceResponse<B>, actix_web::Error> {
+    REQUEST_QUERIES.scope(totals, response).await
+}
+
+/// Count a request and time it, labelled by route template rather than
+/// path, so `/archive/{slug}` is one series however many issues there are.
+pub async fn record_request<B: MessageBody>(
+    method: Method,
+    response: impl Future<Output = Result<ServiceResponse<B>, actix_web::Error>>,
+) -> Result<ServiceResponse<B>, actix_web::Error> {
+    let started = Instant::now();
+    let response = response.await?;
+    // Only known once the router has run
+    let route = response
+        .request()
+        .match_pattern()
+        .unwrap_or_else(|| UNMATCHED_ROUTE.to_string());
+    let status = response.status().as_u16().to_string();
+    let labels = [("method", method.to_string()), ("route", route), ("status", status)];
+    increment_counter!("http_requests_total", &labels);
+    histogram!("http_request_duration_seconds", started.elapsed().as_secs_f64(), &labels);
+    Ok(response)
+}
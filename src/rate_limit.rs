@@ -0,0 +1,428 @@
+use crate::configuration::{BucketSettings, RateLimitSettings, RateLimitStore};
+use crate::monitoring::{timed_begin, TimedQuery};
+use crate::shutdown::Shutdown;
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::HttpResponse;
+use sqlx::PgPool;
+use std::collections::HashMap;
+use std::future::{ready, Future, Ready};
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// How often buckets that are full again are dropped
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+// Probes and scrapes come often, and from the platform itself
+const UNLIMITED_PATHS: [&str; 3] = ["/health_check", "/health/", "/metrics"];
+
+/// An address, or a range of them in CIDR notation such as `10.0.0.0/8`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Network {
+    address: IpAddr,
+    prefix_length: u32,
+}
+
+impl Network {
+    fn parse(network: &str) -> Result<Network, String> {
+        let invalid = || format!("rate_limit.trusted_proxies: `{}` is not an address or CIDR range.", network);
+        let (address, prefix_length) = match network.split_once('/') {
+            Some((address, prefix_length)) => (address, Some(prefix_length)),
+            None => (network, None),
+        };
+        let address: IpAddr = address.trim().parse().map_err(|_| invalid())?;
+        let bits = if address.is_ipv4() { 32 } else { 128 };
+        let prefix_length = match prefix_length {
+            Some(prefix_length) => prefix_length.trim().parse().map_err(|_| invalid())?,
+            None => bits,
+        };
+        if prefix_length > bits {
+            return Err(invalid());
+        }
+        Ok(Network { address, prefix_length })
+    }
+
+    fn contains(&self, address: IpAddr) -> bool {
+        // IPv4 clients of a dual-stack socket show up as mapped IPv6 addresses
+        let address = match address {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(address, IpAddr::V4),
+            v4 => v4,
+        };
+        match (self.address, address) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix_length).unwrap_or(0);
+                u32::from(network) & mask == u32::from(address) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix_length).unwrap_or(0);
+                u128::from(network) & mask == u128::from(address) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Parse `rate_limit.trusted_proxies`, reporting every entry that is invalid.
+pub fn parse_trusted_proxies(proxies: &[String]) -> Result<TrustedProxies, Vec<String>> {
+    let mut problems = Vec::new();
+    let mut networks = Vec::new();
+    for proxy in proxies {
+        match Network::parse(proxy) {
+            Ok(network) => networks.push(network),
+            Err(problem) => problems.push(problem),
+        }
+    }
+    if problems.is_empty() {
+        Ok(TrustedProxies(Arc::new(networks)))
+    } else {
+        Err(problems)
+    }
+}
+
+/// The proxies whose `X-Forwarded-For` is believed.
+#[derive(Clone, Debug)]
+pub struct TrustedProxies(Arc<Vec<Network>>);
+
+impl TrustedProxies {
+    fn trusts(&self, address: IpAddr) -> bool {
+        self.0.iter().any(|network| network.contains(address))
+    }
+
+    /// The client's address: the peer's, unless it is a trusted proxy, in
+    /// which case the nearest address in `X-Forwarded-For` that is not a
+    /// trusted proxy. The entries are read from the right, as everything
+    /// left of what our proxies added is the client's to make up.
+    pub fn client_ip(&self, request: &ServiceRequest) -> Option<IpAddr> {
+        let peer = request.peer_addr()?.ip();
+        if !self.trusts(peer) {
+            return Some(peer);
+        }
+        let headers: Vec<_> = request.headers().get_all("x-forwarded-for").collect();
+        // The last trusted hop, should the list end or turn to garbage
+        let mut client = peer;
+        for value in headers.into_iter().rev() {
+            let value = match value.to_str() {
+                Ok(value) => value,
+                Err(_) => return Some(client),
+            };
+            for entry in value.rsplit(',') {
+                match entry.trim().parse() {
+                    Ok(address) => {
+                        client = address;
+                        if !self.trusts(address) {
+                            return Some(client);
+                        }
+                    }
+                    Err(_) => return Some(client),
+                }
+            }
+        }
+        Some(client)
+    }
+}
+
+/// Up to `capacity` requests at once, then one for every `refill_every`.
+#[derive(Clone, Copy, Debug)]
+pub struct Bucket {
+    capacity: u32,
+    refill_every: Duration,
+}
+
+impl From<BucketSettings> for Bucket {
+    fn from(settings: BucketSettings) -> Self {
+        Bucket {
+            capacity: settings.capacity.max(1),
+            refill_every: Duration::from_millis(settings.refill_every_milliseconds.max(1)),
+        }
+    }
+}
+
+impl Bucket {
+    /// Refill a bucket holding `tokens` for `elapsed`, then take a token
+    /// from it if there is one. Returns the tokens left, and the decision.
+    fn take(&self, tokens: f64, elapsed: Duration) -> (f64, Decision) {
+        let capacity = f64::from(self.capacity);
+        let refill_every = self.refill_every.as_secs_f64();
+        let tokens = (tokens + elapsed.as_secs_f64() / refill_every).min(capacity);
+        let allowed = tokens >= 1.0;
+        let tokens = if allowed { tokens - 1.0 } else { tokens };
+        let decision = Decision {
+            allowed,
+            limit: self.capacity,
+            remaining: tokens.floor() as u32,
+            retry_after: if allowed {
+                Duration::ZERO
+            } else {
+                Duration::from_secs_f64((1.0 - tokens) * refill_every)
+            },
+            reset_after: Duration::from_secs_f64((capacity - tokens) * refill_every),
+        };
+        (tokens, decision)
+    }
+}
+
+/// Whether a request may go ahead, and what to tell the client either way.
+#[derive(Clone, Copy, Debug)]
+pub struct Decision {
+    pub allowed: bool,
+    pub limit: u32,
+    pub remaining: u32,
+    // Until a request would be allowed again
+    pub retry_after: Duration,
+    // Until the bucket is full again
+    pub reset_after: Duration,
+}
+
+impl Decision {
+    fn allow(bucket: Bucket) -> Self {
+        Decision {
+            allowed: true,
+            limit: bucket.capacity,
+            remaining: bucket.capacity,
+            retry_after: Duration::ZERO,
+            reset_after: Duration::ZERO,
+        }
+    }
+
+    /// A 429, with `RateLimit-*` headers and `Retry-After` in whole seconds.
+    pub fn too_many_requests(&self) -> HttpResponse {
+        let retry_after = whole_seconds(self.retry_after).max(1);
+        HttpResponse::TooManyRequests()
+            .insert_header(("RateLimit-Limit", self.limit.to_string()))
+            .insert_header(("RateLimit-Remaining", self.remaining.to_string()))
+            .insert_header(("RateLimit-Reset", whole_seconds(self.reset_after).to_string()))
+            .insert_header(("Retry-After", retry_after.to_string()))
+            .body(format!("Too many requests, retry in {} seconds.", retry_after))
+    }
+}
+
+fn whole_seconds(duration: Duration) -> u64 {
+    duration.as_secs() + u64::from(duration.subsec_nanos() > 0)
+}
+
+/// What a request is counted against.
+#[derive(Clone, Copy, Debug)]
+pub enum RateLimitKey<'a> {
+    Ip(IpAddr),
+    // Compared case-insensitively
+    Email(&'a str),
+    // An authenticated admin's user id
+    User(&'a str),
+}
+
+struct MemoryBucket {
+    tokens: f64,
+    updated_at: Instant,
+    full_at: Instant,
+}
+
+#[derive(Clone)]
+enum Store {
+    Memory(Arc<Mutex<HashMap<String, MemoryBucket>>>),
+    Postgres(PgPool),
+}
+
+/// Token buckets per client IP, email address and admin, kept in memory or
+/// in Postgres as `rate_limit.store` says.
+#[derive(Clone)]
+pub struct RateLimiter {
+    store: Store,
+    trusted_proxies: TrustedProxies,
+    per_ip: Bucket,
+    per_email: Bucket,
+    per_user: Bucket,
+}
+
+impl RateLimiter {
+    pub fn from_settings(settings: &RateLimitSettings, db_pool: PgPool) -> Result<RateLimiter, Vec<String>> {
+        let store = match settings.store {
+            RateLimitStore::Memory => Store::Memory(Arc::new(Mutex::new(HashMap::new()))),
+            RateLimitStore::Postgres => Store::Postgres(db_pool),
+        };
+        Ok(RateLimiter {
+            store,
+            trusted_proxies: parse_trusted_proxies(&settings.trusted_proxies)?,
+            per_ip: settings.per_ip.into(),
+            per_email: settings.per_email.into(),
+            per_user: settings.per_user.into(),
+        })
+    }
+
+    pub fn trusted_proxies(&self) -> &TrustedProxies {
+        &self.trusted_proxies
+    }
+
+    /// Take a token from the bucket of `key`. Should the store fail, the
+    /// request is let through: an outage is no reason to turn users away.
+    pub async fn check(&self, key: RateLimitKey<'_>) -> Decision {
+        let (key, bucket) = match key {
+            RateLimitKey::Ip(address) => (format!("ip:{}", address), self.per_ip),
+            RateLimitKey::Email(email) => (format!("email:{}", email.trim().to_lowercase()), self.per_email),
+            RateLimitKey::User(user_id) => (format!("user:{}", user_id), self.per_user),
+        };
+        let decision = match &self.store {
+            Store::Memory(buckets) => Ok(take_in_memory(buckets, key, bucket)),
+            Store::Postgres(pool) => take_in_postgres(pool, &key, bucket).await,
+        };
+        decision.unwrap_or_else(|e| {
+            tracing::error!("Failed to check the rate limit: {:?}", e);
+            Decision::allow(bucket)
+        })
+    }
+
+    /// Forget the buckets that are full again, as a new one would be.
+    pub async fn prune(&self) -> Result<(), sqlx::Error> {
+        match &self.store {
+            Store::Memory(buckets) => {
+                let now = Instant::now();
+                buckets.lock().unwrap().retain(|_, bucket| bucket.full_at > now);
+            }
+            Store::Postgres(pool) => {
+                sqlx::query!("DELETE FROM rate_limit_buckets WHERE full_at <= now()")
+                    .execute(pool)
+                    .timed("rate_limit.prune")
+                    .await
+                    .map_err(|e| {
+                        tracing::error!("Failed to execute query: {:?}", e);
+                        e
+                    })?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn take_in_memory(buckets: &Mutex<HashMap<String, MemoryBucket>>, key: String, bucket: Bucket) -> Decision {
+    let now = Instant::now();
+    let mut buckets = buckets.lock().unwrap();
+    let entry = buckets.entry(key).or_insert(MemoryBucket {
+        tokens: f64::from(bucket.capacity),
+        updated_at: now,
+        full_at: now,
+    });
+    let (tokens, decision) = bucket.take(entry.tokens, now.duration_since(entry.updated_at));
+    *entry = MemoryBucket {
+        tokens,
+        updated_at: now,
+        full_at: now + decision.reset_after,
+    };
+    decision
+}
+
+async fn take_in_postgres(pool: &PgPool, key: &str, bucket: Bucket) -> Result<Decision, sqlx::Error> {
+    let mut transaction = timed_begin(pool).await?;
+    sqlx::query!(
+        r#"
+        INSERT INTO rate_limit_buckets (key, tokens, updated_at, full_at)
+        VALUES ($1, $2, now(), now())
+        ON CONFLICT (key) DO NOTHING
+        "#,
+        key,
+        f64::from(bucket.capacity)
+    )
+    .execute(&mut transaction)
+    .timed("rate_limit.create_bucket")
+    .await?;
+    // Locked until the transaction ends, so concurrent requests take turns
+    let r = sqlx::query!(
+        r#"
+        SELECT tokens, EXTRACT(EPOCH FROM now() - updated_at)::float8 AS "elapsed_seconds!"
+        FROM rate_limit_buckets
+        WHERE key = $1
+        FOR UPDATE
+        "#,
+        key
+    )
+    .fetch_one(&mut transaction)
+    .timed_one("rate_limit.lock_bucket")
+    .await?;
+    let (tokens, decision) = bucket.take(r.tokens, Duration::from_secs_f64(r.elapsed_seconds.max(0.0)));
+    sqlx::query!(
+        r#"
+        UPDATE rate_limit_buckets
+        SET tokens = $2, updated_at = now(), full_at = now() + make_interval(secs => $3)
+        WHERE key = $1
+        "#,
+        key,
+        tokens,
+        decision.reset_after.as_secs_f64()
+    )
+    .execute(&mut transaction)
+    .timed("rate_limit.update_bucket")
+    .await?;
+    transaction.commit().await?;
+    Ok(decision)
+}
+
+/// Prune `rate_limiter` every minute until the shutdown is triggered.
+pub async fn prune_periodically(rate_limiter: RateLimiter, shutdown: Shutdown) {
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(PRUNE_INTERVAL) => {}
+            _ = shutdown.triggered() => return,
+        }
+        // Already logged, and there is always the next round
+        let _ = rate_limiter.prune().await;
+    }
+}
+
+/// Answers with a 429 once a client IP runs out of tokens, before the
+/// request reaches a handler. Health checks and `/metrics` are let through.
+pub struct RateLimitByIp(pub RateLimiter);
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimitByIp
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Transform = RateLimitByIpMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimitByIpMiddleware {
+            service: Rc::new(service),
+            rate_limiter: self.0.clone(),
+        }))
+    }
+}
+
+pub struct RateLimitByIpMiddleware<S> {
+    service: Rc<S>,
+    rate_limiter: RateLimiter,
+}
+
+impl<S, B> Service<ServiceRequest> for RateLimitByIpMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, request: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let rate_limiter = self.rate_limiter.clone();
+        Box::pin(async move {
+            let unlimited = UNLIMITED_PATHS.iter().any(|path| request.path().starts_with(path));
+            if let (false, Some(client_ip)) = (unlimited, rate_limiter.trusted_proxies().client_ip(&request)) {
+                let decision = rate_limiter.check(RateLimitKey::Ip(client_ip)).await;
+                if !decision.allowed {
+                    tracing::warn!(%client_ip, "Rate limited a client IP");
+                    let (request, _) = request.into_parts();
+                    let response = ServiceResponse::new(request, decision.too_many_requests());
+                    return Ok(response.map_into_right_body());
+                }
+            }
+            Ok(service.call(request).await?.map_into_left_body())
+        })
+    }
+}
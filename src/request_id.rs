@@ -0,0 +1,130 @@
+use actix_web::body::{to_bytes, BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::error::ErrorInternalServerError;
+use actix_web::http::header::{self, HeaderMap, HeaderName, HeaderValue};
+use actix_web::HttpMessage;
+use std::future::Future;
+use uuid::Uuid;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+// Longer ids, or ids with other characters, are replaced rather than logged
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+tokio::task_local! {
+    static CURRENT: RequestId;
+}
+
+/// Identifies a request in our logs, in the response and in the calls made
+/// while handling it, so a user's report can be matched with what we logged.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RequestId(String);
+
+impl RequestId {
+    /// The caller's `X-Request-Id`, if it is one we are willing to log, or a
+    /// new one.
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        headers
+            .get(REQUEST_ID_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .filter(|id| is_valid(id))
+            .map(|id| RequestId(id.to_string()))
+            .unwrap_or_else(|| RequestId(Uuid::new_v4().to_string()))
+    }
+
+    /// The id of the request being handled, if any.
+    pub fn current() -> Option<Self> {
+        CURRENT.try_with(Clone::clone).ok()
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::fmt::Display for RequestId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+fn is_valid(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_REQUEST_ID_LENGTH
+        && id.chars().all(|c| c.is_ascii_alphanumeric() || "-_.:".contains(c))
+}
+
+/// Give the request an id, for the root span and the handlers to find.
+pub fn assign_request_id(request: &ServiceRequest) -> RequestId {
+    let request_id = RequestId::from_headers(request.headers());
+    request.extensions_mut().insert(request_id.clone());
+    request_id
+}
+
+/// Handle the request with its id as `RequestId::current`, and echo the id
+/// on the response.
+pub async fn with_request_id<B: MessageBody>(
+    request_id: RequestId,
+    response: impl Future<Output = Result<ServiceResponse<B>, actix_web::Error>>,
+) -> Result<ServiceResponse<B>, actix_web::Error> {
+    let mut response = CURRENT.scope(request_id.clone(), response).await?;
+    // Always a valid header value, having been either checked or generated
+    if let Ok(value) = HeaderValue::from_str(request_id.as_str()) {
+        response
+            .headers_mut()
+            .insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+    }
+    Ok(response)
+}
+
+#[derive(serde::Serialize)]
+struct Problem {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    title: &'static str,
+    status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+}
+
+/// Turn error responses without a body of their own, or with a plain text
+/// one, into RFC 7807 problem details that name the request id. Responses
+/// that are JSON already, such as the readiness report, are left alone.
+pub async fn problem_details<B>(
+    response: impl Future<Output = Result<ServiceResponse<B>, actix_web::Error>>,
+) -> Result<ServiceResponse<BoxBody>, actix_web::Error>
+where
+    B: MessageBody + 'static,
+{
+    let response = response.await?;
+    let status = response.status();
+    let content_type = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("text/plain");
+    if !(status.is_client_error() || status.is_server_error()) || !content_type.starts_with("text/plain") {
+        return Ok(response.map_into_boxed_body());
+    }
+    let (request, response) = response.into_parts();
+    let (mut head, body) = response.into_parts();
+    let detail = to_bytes(body).await.map_err(|e| {
+        let e: Box<dyn std::error::Error> = e.into();
+        ErrorInternalServerError(e.to_string())
+    })?;
+    let problem = Problem {
+        kind: "about:blank",
+        title: status.canonical_reason().unwrap_or("Error"),
+        status: status.as_u16(),
+        detail: Some(String::from_utf8_lossy(&detail).into_owned()).filter(|detail| !detail.is_empty()),
+        request_id: request.extensions().get::<RequestId>().map(ToString::to_string),
+    };
+    let body = serde_json::to_vec(&problem).expect("A problem always serializes");
+    head.headers_mut().insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/problem+json"),
+    );
+    Ok(ServiceResponse::new(request, head.set_body(BoxBody::new(body))))
+}
@@ -0,0 +1,117 @@
+use crate::models::admin::Admin;
+use crate::rate_limit::{RateLimitKey, RateLimiter};
+use crate::telemetry::{log_level, LogLevel};
+use actix_web::dev::Payload;
+use actix_web::error::{ErrorInternalServerError, InternalError};
+use actix_web::http::header;
+use actix_web::{web, FromRequest, HttpRequest, HttpResponse};
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
+
+/// An admin, authenticated by the API token `create-admin` printed, sent as
+/// `Authorization: Bearer <token>`. Requests without a valid one get a 401,
+/// and admins who ran out of `rate_limit.per_user` tokens a 429.
+pub struct AuthenticatedAdmin {
+    pub user_id: String,
+}
+
+impl FromRequest for AuthenticatedAdmin {
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(request: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let token = bearer_token(request);
+        let pool = request.app_data::<web::Data<PgPool>>().cloned();
+        let rate_limiter = request.app_data::<web::Data<RateLimiter>>().cloned();
+        Box::pin(async move {
+            let token = token.ok_or_else(unauthorized)?;
+            let pool = pool.ok_or_else(|| ErrorInternalServerError("The database pool is not configured"))?;
+            let rate_limiter =
+                rate_limiter.ok_or_else(|| ErrorInternalServerError("The rate limiter is not configured"))?;
+            let user_id = match Admin::authenticate(&pool, &token).await {
+                Ok(Some(user_id)) => user_id,
+                Ok(None) => return Err(unauthorized()),
+                Err(e) => return Err(ErrorInternalServerError(e)),
+            };
+            let decision = rate_limiter.check(RateLimitKey::User(&user_id)).await;
+            if !decision.allowed {
+                return Err(InternalError::from_response("Rate limited", decision.too_many_requests()).into());
+            }
+            Ok(AuthenticatedAdmin { user_id })
+        })
+    }
+}
+
+fn bearer_token(request: &HttpRequest) -> Option<String> {
+    let value = request.headers().get(header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, token) = value.split_once(' ')?;
+    if scheme.eq_ignore_ascii_case("bearer") && !token.trim().is_empty() {
+        Some(token.trim().to_string())
+    } else {
+        None
+    }
+}
+
+fn unauthorized() -> actix_web::Error {
+    let response = HttpResponse::Unauthorized()
+        .insert_header((header::WWW_AUTHENTICATE, "Bearer"))
+        .finish();
+    InternalError::from_response("Missing or invalid admin token", response).into()
+}
+
+#[derive(serde::Serialize)]
+struct LogLevelReport {
+    filter: String,
+    default: String,
+    reverts_at: Option<DateTime<Utc>>,
+}
+
+impl From<&LogLevel> for LogLevelReport {
+    fn from(log_level: &LogLevel) -> Self {
+        LogLevelReport {
+            filter: log_level.current(),
+            default: log_level.default_filter().to_string(),
+            reverts_at: log_level.reverts_at(),
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+pub struct LogLevelChange {
+    // `RUST_LOG` directives, such as `info,sqlx=trace`
+    filter: String,
+    // Go back to the filter at startup after this long; never if not set
+    revert_after_seconds: Option<u64>,
+}
+
+#[tracing::instrument(name = "Getting the log level", skip(admin), fields(admin_id = %admin.user_id))]
+pub async fn get_log_level(admin: AuthenticatedAdmin) -> HttpResponse {
+    match log_level() {
+        Some(log_level) => HttpResponse::Ok().json(LogLevelReport::from(log_level)),
+        // Only if no subscriber was installed, so there is nothing to filter
+        None => HttpResponse::ServiceUnavailable().finish(),
+    }
+}
+
+/// `PUT /admin/log-level`: filter logs with new directives, for good or
+/// for `revert_after_seconds`. Responds with the filter now in use.
+#[tracing::instrument(
+    name = "Changing the log level",
+    skip(admin, body),
+    fields(admin_id = %admin.user_id, filter = %body.filter)
+)]
+pub async fn set_log_level(admin: AuthenticatedAdmin, body: web::Json<LogLevelChange>) -> HttpResponse {
+    let log_level = match log_level() {
+        Some(log_level) => log_level,
+        None => return HttpResponse::ServiceUnavailable().finish(),
+    };
+    let revert_after = body.revert_after_seconds.map(Duration::from_secs);
+    if let Err(e) = log_level.set(&body.filter, revert_after) {
+        return HttpResponse::BadRequest().body(e.to_string());
+    }
+    tracing::info!("Changed the log filter");
+    HttpResponse::Ok().json(LogLevelReport::from(log_level))
+}
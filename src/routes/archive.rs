@@ -0,0 +1,301 @@
+use crate::models::newsletter_issue::NewsletterIssue;
+use crate::startup::{ApplicationBaseUrl, NewsletterTitle};
+use crate::templating::{escape_html, NewsletterLayout, NewsletterTemplate, Personalization};
+use actix_web::http::header::{
+    EntityTag, ETag, Header, HttpDate, IfModifiedSince, IfNoneMatch, LastModified, IF_NONE_MATCH,
+};
+use actix_web::{web, HttpRequest, HttpResponse, HttpResponseBuilder};
+use chrono::{DateTime, TimeZone, Utc};
+use sqlx::PgPool;
+use std::time::SystemTime;
+
+// How many issues the archive page and the feeds list
+const ARCHIVE_LIMIT: i64 = 100;
+const FEED_LIMIT: i64 = 20;
+// Stands in for `{{ name }}` in issues read outside of an inbox
+const ARCHIVE_READER_NAME: &str = "reader";
+
+#[tracing::instrument(name = "Showing the newsletter archive", skip(request, pool, base_url, title))]
+pub async fn archive(
+    request: HttpRequest,
+    pool: web::Data<PgPool>,
+    base_url: web::Data<ApplicationBaseUrl>,
+    title: web::Data<NewsletterTitle>,
+) -> HttpResponse {
+    let last_modified = match NewsletterIssue::archive_last_modified(&pool).await {
+        Ok(last_modified) => last_modified.unwrap_or_else(|| Utc.timestamp(0, 0)),
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+    let validators = Validators::new(last_modified);
+    if validators.is_fresh(&request) {
+        return validators.apply(HttpResponse::NotModified()).finish();
+    }
+    let issues = match NewsletterIssue::find_archived(&pool, ARCHIVE_LIMIT).await {
+        Ok(issues) => issues,
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+    let items: String = issues
+        .iter()
+        .map(|issue| {
+            format!(
+                r#"<li><a href="{}">{}</a> <time datetime="{}">{}</time></li>"#,
+                escape_html(&issue_url(&base_url, issue)),
+                escape_html(&issue.title),
+                published_at(issue).to_rfc3339(),
+                published_at(issue).format("%B %e, %Y"),
+            )
+        })
+        .collect();
+    let body = format!(
+        r#"<h1>{title}</h1>
+<p><a href="{base}/feed.xml">Atom</a> · <a href="{base}/feed.rss">RSS</a></p>
+<ul>{items}</ul>"#,
+        title = escape_html(&title.0),
+        base = escape_html(&base_url.0),
+        items = items,
+    );
+    validators
+        .apply(HttpResponse::Ok())
+        .content_type("text/html; charset=utf-8")
+        .body(html_page(&title.0, &base_url, &body))
+}
+
+#[tracing::instrument(name = "Showing an archived newsletter issue", skip(request, pool, base_url, title))]
+pub async fn archived_issue(
+    request: HttpRequest,
+    slug: web::Path<String>,
+    pool: web::Data<PgPool>,
+    base_url: web::Data<ApplicationBaseUrl>,
+    title: web::Data<NewsletterTitle>,
+) -> HttpResponse {
+    // Private and unsent issues are not found, rather than forbidden,
+    // so their slugs cannot be probed for
+    let issue = match NewsletterIssue::get_archived_by_slug(&pool, &slug).await {
+        Ok(issue) => issue,
+        Err(sqlx::Error::RowNotFound) => return HttpResponse::NotFound().finish(),
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+    let validators = Validators::new(issue.updated_at);
+    if validators.is_fresh(&request) {
+        return validators.apply(HttpResponse::NotModified()).finish();
+    }
+    let content = match render_content(&issue, &base_url) {
+        Some(content) => content,
+        None => return HttpResponse::InternalServerError().finish(),
+    };
+    let body = format!(
+        r#"<p><a href="{archive}">{newsletter}</a></p>
+<article>
+<h1>{title}</h1>
+<time datetime="{published}">{published_display}</time>
+{content}
+</article>"#,
+        archive = escape_html(&archive_url(&base_url)),
+        newsletter = escape_html(&title.0),
+        title = escape_html(&issue.title),
+        published = published_at(&issue).to_rfc3339(),
+        published_display = published_at(&issue).format("%B %e, %Y"),
+        content = content,
+    );
+    validators
+        .apply(HttpResponse::Ok())
+        .content_type("text/html; charset=utf-8")
+        .body(html_page(&issue.title, &base_url, &body))
+}
+
+#[tracing::instrument(name = "Serving the Atom feed", skip(request, pool, base_url, title))]
+pub async fn atom_feed(
+    request: HttpRequest,
+    pool: web::Data<PgPool>,
+    base_url: web::Data<ApplicationBaseUrl>,
+    title: web::Data<NewsletterTitle>,
+) -> HttpResponse {
+    let (validators, issues) = match feed_issues(&request, &pool).await {
+        Ok(feed) => feed,
+        Err(response) => return response,
+    };
+    let mut entries = String::new();
+    for issue in &issues {
+        let content = match render_content(issue, &base_url) {
+            Some(content) => content,
+            None => return HttpResponse::InternalServerError().finish(),
+        };
+        let url = escape_html(&issue_url(&base_url, issue));
+        entries.push_str(&format!(
+            r#"<entry><title>{}</title><id>{}</id><link href="{}"/><updated>{}</updated><published>{}</published><content type="html">{}</content></entry>"#,
+            escape_html(&issue.title),
+            url,
+            url,
+            issue.updated_at.to_rfc3339(),
+            published_at(issue).to_rfc3339(),
+            escape_html(&content),
+        ));
+    }
+    let body = format!(
+        r#"<?xml version="1.0" encoding="utf-8"?>
+<feed xmlns="http://www.w3.org/2005/Atom"><title>{title}</title><id>{archive}</id><link href="{archive}"/><link rel="self" href="{base}/feed.xml"/><updated>{updated}</updated><author><name>{title}</name></author>{entries}</feed>"#,
+        title = escape_html(&title.0),
+        archive = escape_html(&archive_url(&base_url)),
+        base = escape_html(&base_url.0),
+        updated = validators.last_modified.to_rfc3339(),
+        entries = entries,
+    );
+    validators
+        .apply(HttpResponse::Ok())
+        .content_type("application/atom+xml; charset=utf-8")
+        .body(body)
+}
+
+#[tracing::instrument(name = "Serving the RSS feed", skip(request, pool, base_url, title))]
+pub async fn rss_feed(
+    request: HttpRequest,
+    pool: web::Data<PgPool>,
+    base_url: web::Data<ApplicationBaseUrl>,
+    title: web::Data<NewsletterTitle>,
+) -> HttpResponse {
+    let (validators, issues) = match feed_issues(&request, &pool).await {
+        Ok(feed) => feed,
+        Err(response) => return response,
+    };
+    let mut items = String::new();
+    for issue in &issues {
+        let content = match render_content(issue, &base_url) {
+            Some(content) => content,
+            None => return HttpResponse::InternalServerError().finish(),
+        };
+        let url = escape_html(&issue_url(&base_url, issue));
+        items.push_str(&format!(
+            r#"<item><title>{}</title><link>{}</link><guid isPermaLink="true">{}</guid><pubDate>{}</pubDate><description>{}</description></item>"#,
+            escape_html(&issue.title),
+            url,
+            url,
+            published_at(issue).to_rfc2822(),
+            escape_html(&content),
+        ));
+    }
+    let body = format!(
+        r#"<?xml version="1.0" encoding="utf-8"?>
+<rss version="2.0"><channel><title>{title}</title><link>{archive}</link><description>{title}</description><lastBuildDate>{updated}</lastBuildDate>{items}</channel></rss>"#,
+        title = escape_html(&title.0),
+        archive = escape_html(&archive_url(&base_url)),
+        updated = validators.last_modified.to_rfc2822(),
+        items = items,
+    );
+    validators
+        .apply(HttpResponse::Ok())
+        .content_type("application/rss+xml; charset=utf-8")
+        .body(body)
+}
+
+// Everything both feeds need; `Err` carries the response to return as is
+async fn feed_issues(
+    request: &HttpRequest,
+    pool: &PgPool,
+) -> Result<(Validators, Vec<NewsletterIssue>), HttpResponse> {
+    let last_modified = NewsletterIssue::archive_last_modified(pool)
+        .await
+        .map_err(|_| HttpResponse::InternalServerError().finish())?
+        .unwrap_or_else(|| Utc.timestamp(0, 0));
+    let validators = Validators::new(last_modified);
+    if validators.is_fresh(request) {
+        return Err(validators.apply(HttpResponse::NotModified()).finish());
+    }
+    let issues = NewsletterIssue::find_archived(pool, FEED_LIMIT)
+        .await
+        .map_err(|_| HttpResponse::InternalServerError().finish())?;
+    Ok((validators, issues))
+}
+
+/// The cache validators of a resource last changed at `last_modified`.
+struct Validators {
+    etag: EntityTag,
+    last_modified: DateTime<Utc>,
+}
+
+impl Validators {
+    fn new(last_modified: DateTime<Utc>) -> Self {
+        Self {
+            etag: EntityTag::new_weak(last_modified.timestamp_nanos().to_string()),
+            last_modified,
+        }
+    }
+
+    /// Whether the client's cached copy is still current, so a 304 will do.
+    fn is_fresh(&self, request: &HttpRequest) -> bool {
+        // If-None-Match wins over If-Modified-Since when both are sent
+        if request.headers().contains_key(IF_NONE_MATCH) {
+            match IfNoneMatch::parse(request) {
+                Ok(IfNoneMatch::Any) => true,
+                Ok(IfNoneMatch::Items(tags)) => tags.iter().any(|tag| tag.weak_eq(&self.etag)),
+                Err(_) => false,
+            }
+        } else {
+            match IfModifiedSince::parse(request) {
+                // HTTP dates only have a one second resolution
+                Ok(IfModifiedSince(since)) => {
+                    DateTime::<Utc>::from(SystemTime::from(since)).timestamp()
+                        >= self.last_modified.timestamp()
+                }
+                Err(_) => false,
+            }
+        }
+    }
+
+    fn apply(&self, mut response: HttpResponseBuilder) -> HttpResponseBuilder {
+        response
+            .insert_header(ETag(self.etag.clone()))
+            .insert_header(LastModified(HttpDate::from(SystemTime::from(self.last_modified))));
+        response
+    }
+}
+
+// The issue's HTML without the email layout around it
+fn render_content(issue: &NewsletterIssue, base_url: &ApplicationBaseUrl) -> Option<String> {
+    let template = NewsletterTemplate::compose(&issue.title, &issue.markdown, &NewsletterLayout::bare())
+        .map_err(|e| {
+            tracing::error!(error.cause_chain = ?e, "Failed to render an archived issue");
+            e
+        })
+        .ok()?;
+    let unsubscribe_url = archive_url(base_url);
+    Some(
+        template
+            .render(&Personalization {
+                name: ARCHIVE_READER_NAME,
+                unsubscribe_url: &unsubscribe_url,
+            })
+            .html,
+    )
+}
+
+fn html_page(title: &str, base_url: &ApplicationBaseUrl, body: &str) -> String {
+    format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<title>{title}</title>
+<link rel="alternate" type="application/atom+xml" href="{base}/feed.xml">
+<link rel="alternate" type="application/rss+xml" href="{base}/feed.rss">
+</head>
+<body>
+{body}
+</body>
+</html>"#,
+        title = escape_html(title),
+        base = escape_html(&base_url.0),
+        body = body,
+    )
+}
+
+fn archive_url(base_url: &ApplicationBaseUrl) -> String {
+    format!("{}/archive", base_url.0)
+}
+
+fn issue_url(base_url: &ApplicationBaseUrl, issue: &NewsletterIssue) -> String {
+    format!("{}/archive/{}", base_url.0, issue.slug)
+}
+
+fn published_at(issue: &NewsletterIssue) -> DateTime<Utc> {
+    issue.sent_at.unwrap_or(issue.updated_at)
+}
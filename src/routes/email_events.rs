@@ -0,0 +1,144 @@
+use crate::configuration::EmailEventsSettings;
+use crate::models::issue_delivery::{DeliveryStatus, IssueDelivery};
+use crate::models::suppression::{Suppression, SuppressionReason};
+use actix_web::{web, HttpRequest, HttpResponse};
+use hmac::{Hmac, Mac};
+use secrecy::{ExposeSecret, Secret};
+use sha2::Sha256;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+// Hex-encoded HMAC-SHA256 of the raw request body, keyed with the webhook secret
+const SIGNATURE_HEADER: &str = "X-Webhook-Signature";
+
+/// The webhook records we act on, in the email provider's (Postmark's) format.
+#[derive(serde::Deserialize, Debug)]
+#[serde(tag = "RecordType")]
+enum EmailEvent {
+    Bounce {
+        #[serde(rename = "Type")]
+        bounce_type: String,
+        #[serde(rename = "Email")]
+        email: String,
+        #[serde(rename = "Metadata", default)]
+        metadata: EventMetadata,
+    },
+    SpamComplaint {
+        #[serde(rename = "Email")]
+        email: String,
+        #[serde(rename = "Metadata", default)]
+        metadata: EventMetadata,
+    },
+    Delivery {
+        #[serde(rename = "Recipient")]
+        email: String,
+        #[serde(rename = "Metadata", default)]
+        metadata: EventMetadata,
+    },
+    #[serde(other)]
+    Other,
+}
+
+// Echoed back from what the delivery worker attached to the email
+#[derive(serde::Deserialize, Debug, Default)]
+struct EventMetadata {
+    delivery_id: Option<Uuid>,
+}
+
+#[tracing::instrument(name = "Handling an email event", skip(request, body, pool, settings))]
+pub async fn handle_email_event(
+    request: HttpRequest,
+    body: web::Bytes,
+    pool: web::Data<PgPool>,
+    settings: web::Data<EmailEventsSettings>,
+) -> HttpResponse {
+    let signature = request
+        .headers()
+        .get(SIGNATURE_HEADER)
+        .and_then(|s| s.to_str().ok());
+    match signature {
+        Some(signature) if verify_signature(&settings.webhook_secret, &body, signature) => {}
+        _ => {
+            tracing::warn!("Rejected an email event with a missing or invalid signature");
+            return HttpResponse::Unauthorized().finish();
+        }
+    }
+    let event: EmailEvent = match serde_json::from_slice(&body) {
+        Ok(event) => event,
+        Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+    };
+    match apply_event(&pool, &settings, event).await {
+        Ok(()) => HttpResponse::Ok().finish(),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+async fn apply_event(
+    pool: &PgPool,
+    settings: &EmailEventsSettings,
+    event: EmailEvent,
+) -> Result<(), sqlx::Error> {
+    match event {
+        EmailEvent::Bounce {
+            bounce_type,
+            email,
+            metadata,
+        } => {
+            set_delivery_status(pool, &metadata, DeliveryStatus::Bounced).await?;
+            match bounce_type.as_str() {
+                "HardBounce" => {
+                    tracing::info!("Suppressing an address after a hard bounce");
+                    Suppression::suppress(pool, &email, SuppressionReason::HardBounce).await
+                }
+                "SoftBounce" => {
+                    if Suppression::record_soft_bounce(pool, &email, settings.soft_bounce_threshold)
+                        .await?
+                    {
+                        tracing::info!("Suppressing an address after repeated soft bounces");
+                    }
+                    Ok(())
+                }
+                other => {
+                    tracing::info!(bounce_type = %other, "Ignoring bounce");
+                    Ok(())
+                }
+            }
+        }
+        EmailEvent::SpamComplaint { email, metadata } => {
+            set_delivery_status(pool, &metadata, DeliveryStatus::Complained).await?;
+            tracing::info!("Suppressing an address after a spam complaint");
+            Suppression::suppress(pool, &email, SuppressionReason::Complaint).await
+        }
+        EmailEvent::Delivery { email, metadata } => {
+            set_delivery_status(pool, &metadata, DeliveryStatus::Delivered).await?;
+            Suppression::reset_soft_bounces(pool, &email).await
+        }
+        // Acknowledge it anyway, or the provider keeps retrying
+        EmailEvent::Other => Ok(()),
+    }
+}
+
+async fn set_delivery_status(
+    pool: &PgPool,
+    metadata: &EventMetadata,
+    status: DeliveryStatus,
+) -> Result<(), sqlx::Error> {
+    match metadata.delivery_id {
+        Some(delivery_id) => IssueDelivery::set_status(pool, delivery_id, status).await,
+        None => Ok(()),
+    }
+}
+
+fn verify_signature(secret: &Secret<String>, body: &[u8], signature: &str) -> bool {
+    let signature = signature.trim();
+    let signature = signature.strip_prefix("sha256=").unwrap_or(signature);
+    let signature = match hex::decode(signature) {
+        Ok(signature) => signature,
+        Err(_) => return false,
+    };
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.expose_secret().as_bytes())
+        .expect("HMAC can take a key of any size");
+    mac.update(body);
+    // Constant time, so the signature cannot be guessed byte by byte
+    mac.verify_slice(&signature).is_ok()
+}
@@ -1,5 +1,126 @@
-use actix_web::HttpResponse;
+use crate::issue_delivery_worker::{time_since_last_heartbeat, HEARTBEAT_TIMEOUT};
+use crate::migrations::{migration_status, MigrationState};
+use crate::shutdown::Shutdown;
+use actix_web::{web, HttpResponse};
+use sqlx::PgPool;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::time::Duration;
 
-pub async fn health_check() -> HttpResponse {
+// How long each readiness check may take before it counts as failed
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Kept for the probes configured before `/health/live` and `/health/ready`.
+pub async fn health_check(shutdown: web::Data<Shutdown>) -> HttpResponse {
+    // Tell load balancers to stop sending traffic while we drain
+    if shutdown.is_triggered() {
+        return HttpResponse::ServiceUnavailable().finish();
+    }
     HttpResponse::Ok().finish()
-}
\ No newline at end of file
+}
+
+#[derive(serde::Serialize)]
+struct Check {
+    status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+}
+
+impl Check {
+    fn ok() -> Self {
+        Check { status: "ok", detail: None }
+    }
+
+    fn failed(detail: impl Into<String>) -> Self {
+        Check {
+            status: "failed",
+            detail: Some(detail.into()),
+        }
+    }
+
+    fn is_ok(&self) -> bool {
+        self.detail.is_none()
+    }
+}
+
+#[derive(serde::Serialize)]
+struct HealthReport {
+    status: &'static str,
+    checks: BTreeMap<&'static str, Check>,
+}
+
+/// The process is up. Nothing else is checked, so a dependency being down
+/// does not get it restarted.
+pub async fn liveness() -> HttpResponse {
+    HttpResponse::Ok().json(HealthReport {
+        status: "ok",
+        checks: BTreeMap::new(),
+    })
+}
+
+/// Whether the instance should get traffic: the database answers, its
+/// migrations are applied, a delivery worker is alive and we are not
+/// shutting down. Responds with 503 and the failing checks otherwise.
+#[tracing::instrument(name = "Checking readiness", skip(pool, shutdown))]
+pub async fn readiness(pool: web::Data<PgPool>, shutdown: web::Data<Shutdown>) -> HttpResponse {
+    let (database, migrations, worker) = tokio::join!(
+        with_timeout(check_database(&pool)),
+        with_timeout(check_migrations(&pool)),
+        with_timeout(check_worker(&pool)),
+    );
+    let shutdown = if shutdown.is_triggered() {
+        Check::failed("shutting down")
+    } else {
+        Check::ok()
+    };
+    let checks = BTreeMap::from([
+        ("database", database),
+        ("migrations", migrations),
+        ("worker", worker),
+        ("shutdown", shutdown),
+    ]);
+    if checks.values().all(Check::is_ok) {
+        HttpResponse::Ok().json(HealthReport { status: "ok", checks })
+    } else {
+        HttpResponse::ServiceUnavailable().json(HealthReport {
+            status: "unavailable",
+            checks,
+        })
+    }
+}
+
+async fn with_timeout(check: impl Future<Output = Check>) -> Check {
+    tokio::time::timeout(CHECK_TIMEOUT, check)
+        .await
+        .unwrap_or_else(|_| Check::failed(format!("timed out after {}ms", CHECK_TIMEOUT.as_millis())))
+}
+
+async fn check_database(pool: &PgPool) -> Check {
+    match sqlx::query("SELECT 1").execute(pool).await {
+        Ok(_) => Check::ok(),
+        Err(e) => Check::failed(e.to_string()),
+    }
+}
+
+async fn check_migrations(pool: &PgPool) -> Check {
+    let status = match migration_status(pool).await {
+        Ok(status) => status,
+        Err(e) => return Check::failed(e.to_string()),
+    };
+    let pending = status.iter().filter(|m| m.state == MigrationState::Pending).count();
+    let modified = status.iter().filter(|m| m.state == MigrationState::Modified).count();
+    match (pending, modified) {
+        (0, 0) => Check::ok(),
+        (pending, 0) => Check::failed(format!("{} pending", pending)),
+        (_, modified) => Check::failed(format!("{} modified since they were applied", modified)),
+    }
+}
+
+async fn check_worker(pool: &PgPool) -> Check {
+    match time_since_last_heartbeat(pool).await {
+        Ok(Some(elapsed)) if elapsed <= HEARTBEAT_TIMEOUT => Check::ok(),
+        Ok(Some(elapsed)) => Check::failed(format!("last seen {}s ago", elapsed.as_secs())),
+        Ok(None) => Check::failed("never seen"),
+        Err(e) => Check::failed(e.to_string()),
+    }
+}
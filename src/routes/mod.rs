@@ -1,5 +1,17 @@
+mod admin;
+mod archive;
+mod email_events;
 mod health_check;
+mod newsletters;
 mod subscriptions;
+mod tracking;
+mod version;
 
+pub use admin::*;
+pub use archive::*;
+pub use email_events::*;
 pub use health_check::*;
-pub use subscriptions::*;
\ No newline at end of file
+pub use newsletters::*;
+pub use subscriptions::*;
+pub use tracking::*;
+pub use version::*;
\ No newline at end of file
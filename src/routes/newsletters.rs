@@ -0,0 +1,71 @@
+use crate::models::newsletter_issue::NewsletterIssue;
+use crate::routes::AuthenticatedAdmin;
+use crate::startup::ApplicationBaseUrl;
+use crate::templating::{NewsletterLayout, NewsletterTemplate, Personalization};
+use actix_web::{web, HttpResponse};
+use sqlx::PgPool;
+
+// Shown in place of a real subscriber by the preview endpoint
+const SAMPLE_SUBSCRIBER_NAME: &str = "Jane Doe";
+const SAMPLE_UNSUBSCRIBE_TOKEN: &str = "preview";
+
+#[derive(serde::Deserialize)]
+pub struct BodyData {
+    pub title: String,
+    // Markdown source, with `{{ name }}` and `{{ unsubscribe_url }}` placeholders
+    pub markdown: String,
+    // Open and click tracking, on unless turned off
+    pub tracking_enabled: Option<bool>,
+    // Keeps the issue out of the public archive and feeds
+    pub private: Option<bool>,
+}
+
+#[tracing::instrument(
+    name = "Publishing a newsletter issue",
+    skip(admin, body, pool, layout),
+    fields(admin_id = %admin.user_id, issue_title = %body.title)
+)]
+pub async fn publish_newsletter(
+    admin: AuthenticatedAdmin,
+    body: web::Json<BodyData>,
+    pool: web::Data<PgPool>,
+    layout: web::Data<NewsletterLayout>,
+) -> HttpResponse {
+    // Rendering happens again per subscriber at delivery time; this only
+    // rejects issues that could never be delivered
+    if let Err(e) = NewsletterTemplate::compose(&body.title, &body.markdown, &layout) {
+        return HttpResponse::BadRequest().body(e.to_string());
+    }
+    match NewsletterIssue::insert_for_delivery(&pool, &body).await {
+        Ok(issue) => HttpResponse::Ok().json(issue),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+#[tracing::instrument(
+    name = "Previewing a newsletter issue",
+    skip(admin, body, layout, base_url),
+    fields(admin_id = %admin.user_id, issue_title = %body.title)
+)]
+pub async fn preview_newsletter(
+    admin: AuthenticatedAdmin,
+    body: web::Json<BodyData>,
+    layout: web::Data<NewsletterLayout>,
+    base_url: web::Data<ApplicationBaseUrl>,
+) -> HttpResponse {
+    match NewsletterTemplate::compose(&body.title, &body.markdown, &layout) {
+        Ok(newsletter) => {
+            let unsubscribe_url = unsubscribe_url(&base_url, SAMPLE_UNSUBSCRIBE_TOKEN);
+            HttpResponse::Ok().json(newsletter.render(&Personalization {
+                name: SAMPLE_SUBSCRIBER_NAME,
+                unsubscribe_url: &unsubscribe_url,
+            }))
+        }
+        Err(e) => HttpResponse::BadRequest().body(e.to_string()),
+    }
+}
+
+/// The link every newsletter carries, served by `unsubscribe`.
+pub fn unsubscribe_url(base_url: &ApplicationBaseUrl, unsubscribe_token: &str) -> String {
+    format!("{}/subscriptions/unsubscribe/{}", base_url.0, unsubscribe_token)
+}
@@ -1,22 +1,29 @@
+use crate::models::suppression::{Suppression, SuppressionReason};
 use actix_web::{web, HttpResponse};
 use sqlx::PgPool;
 use chrono::Utc;
 use uuid::Uuid;
+use crate::monitoring::TimedQuery;
+use crate::rate_limit::{RateLimitKey, RateLimiter};
+use crate::telemetry::Redacted;
+use crate::tracking::new_token;
 
 #[derive(serde::Deserialize)]
 pub struct FormData {
     email: String,
-    name: String
+    name: String,
+    // Whether issues sent to this subscriber may track opens and clicks
+    allow_tracking: Option<bool>,
 }
 
 #[tracing::instrument(
     name = "Adding a new subscriber",
-    skip(form),
+    skip(form, rate_limiter),
     fields(
         // Generate a random unique identifier no longer needed with TracingLogger vs Logger 
         // request_id = %Uuid::new_v4(),
-        subscriber_email = %form.email,
-        subscriber_name = %form.name,
+        subscriber_email = %Redacted(&form.email),
+        subscriber_name = %Redacted(&form.name),
     )
 )]
 // orchestrates the work to be done by calling the required routines and translates their outcome into the proper response according to the rules and conventions of the HTTP protocol.
@@ -24,7 +31,23 @@ pub async fn subscribe(
     form: web::Form<FormData>,
     // Retrieving a connection from the application state!
     pool: web::Data<PgPool>,
+    rate_limiter: web::Data<RateLimiter>,
 ) -> HttpResponse {
+    // Or anyone could have us mail an address over and over
+    let decision = rate_limiter.check(RateLimitKey::Email(&form.email)).await;
+    if !decision.allowed {
+        tracing::warn!("Rate limited an email address");
+        return decision.too_many_requests();
+    }
+    // Addresses that bounced or complained are never mailed again
+    match Suppression::is_suppressed(&pool, &form.email).await {
+        Ok(false) => {}
+        Ok(true) => {
+            tracing::info!("Refusing to subscribe a suppressed address");
+            return HttpResponse::UnprocessableEntity().finish();
+        }
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    }
     match insert_subscriber(&pool, &form).await
     {
         Ok(_) => {
@@ -35,6 +58,41 @@ pub async fn subscribe(
         }
     }
 }
+/// `GET /subscriptions/unsubscribe/{token}`, the link in every newsletter.
+/// The address goes on the suppression list, so it is never mailed again.
+#[tracing::instrument(name = "Unsubscribing a subscriber", skip(token, pool))]
+pub async fn unsubscribe(token: web::Path<String>, pool: web::Data<PgPool>) -> HttpResponse {
+    let email = match subscriber_email_by_unsubscribe_token(&pool, &token).await {
+        Ok(Some(email)) => email,
+        Ok(None) => return HttpResponse::NotFound().finish(),
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+    match Suppression::suppress(&pool, &email, SuppressionReason::Unsubscribed).await {
+        Ok(()) => HttpResponse::Ok()
+            .content_type("text/plain; charset=utf-8")
+            .body("You have been unsubscribed."),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+async fn subscriber_email_by_unsubscribe_token(
+    pool: &PgPool,
+    token: &str,
+) -> Result<Option<String>, sqlx::Error> {
+    let subscriber = sqlx::query!(
+        r#"SELECT email FROM subscriptions WHERE unsubscribe_token = $1"#,
+        token
+    )
+    .fetch_optional(pool)
+    .timed("subscription.find_by_unsubscribe_token")
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(subscriber.map(|subscriber| subscriber.email))
+}
+
 // takes care of the database logic and it has no awareness of the surrounding web framework - i.e. we are not passing web::Form or web::Data wrappers as input types;
 pub async fn insert_subscriber(
     pool: &PgPool,
@@ -42,15 +100,18 @@ pub async fn insert_subscriber(
 ) -> Result<(), sqlx::Error> {
     sqlx::query!(
         r#"
-    INSERT INTO subscriptions (id, email, name, subscribed_at)
-    VALUES ($1, $2, $3, $4)
+    INSERT INTO subscriptions (id, email, name, subscribed_at, allow_tracking, unsubscribe_token)
+    VALUES ($1, $2, $3, $4, $5, $6)
             "#,
         Uuid::new_v4(),
         form.email,
         form.name,
-        Utc::now()
+        Utc::now(),
+        form.allow_tracking.unwrap_or(true),
+        new_token()
     )
     .execute(pool)
+    .timed("subscription.insert_subscriber")
     .await
     .map_err(|e| {
         tracing::error!("Failed to execute query: {:?}", e);
@@ -0,0 +1,38 @@
+use crate::models::issue_delivery::IssueDelivery;
+use actix_web::http::header::{CacheControl, CacheDirective, LOCATION};
+use actix_web::{web, HttpResponse};
+use sqlx::PgPool;
+
+// A 1x1 transparent GIF
+const TRACKING_PIXEL: &[u8] = &[
+    0x47, 0x49, 0x46, 0x38, 0x39, 0x61, 0x01, 0x00, 0x01, 0x00, 0x80, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0xff, 0xff, 0xff, 0x21, 0xf9, 0x04, 0x01, 0x00, 0x00, 0x00, 0x00, 0x2c, 0x00, 0x00,
+    0x00, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x02, 0x02, 0x44, 0x01, 0x00, 0x3b,
+];
+
+#[tracing::instrument(name = "Tracking an email open", skip(token, pool))]
+pub async fn track_open(token: web::Path<String>, pool: web::Data<PgPool>) -> HttpResponse {
+    // The pixel is served no matter what: a broken image in the
+    // subscriber's inbox would not help anybody
+    if let Ok(false) = IssueDelivery::record_open(&pool, &token).await {
+        tracing::warn!("Unknown tracking token");
+    }
+    HttpResponse::Ok()
+        .content_type("image/gif")
+        // Ask clients and proxies not to cache it, so every open reaches us
+        .insert_header(CacheControl(vec![
+            CacheDirective::NoCache,
+            CacheDirective::NoStore,
+            CacheDirective::MustRevalidate,
+        ]))
+        .body(TRACKING_PIXEL)
+}
+
+#[tracing::instrument(name = "Tracking a link click", skip(token, pool))]
+pub async fn track_click(token: web::Path<String>, pool: web::Data<PgPool>) -> HttpResponse {
+    match IssueDelivery::record_click(&pool, &token).await {
+        Ok(Some(url)) => HttpResponse::Found().insert_header((LOCATION, url)).finish(),
+        Ok(None) => HttpResponse::NotFound().finish(),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
@@ -0,0 +1,46 @@
+use crate::build_info::{build_info, BuildInfo};
+use crate::configuration::Environment;
+use crate::migrations::{migration_status, MigrationState};
+use actix_web::{web, HttpResponse};
+use sqlx::PgPool;
+use std::time::Duration;
+
+// The rest of the report is worth having even when the database is slow
+const MIGRATION_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(serde::Serialize)]
+struct VersionReport {
+    #[serde(flatten)]
+    build: BuildInfo,
+    environment: &'static str,
+    // `null` when the database could not tell
+    latest_migration: Option<i64>,
+}
+
+/// `GET /version`: which build is running, where, and how far the
+/// database it talks to has been migrated.
+pub async fn version(db_pool: web::Data<PgPool>, environment: web::Data<Environment>) -> HttpResponse {
+    HttpResponse::Ok().json(VersionReport {
+        build: build_info(),
+        environment: environment.as_str(),
+        latest_migration: latest_migration(&db_pool).await,
+    })
+}
+
+async fn latest_migration(pool: &PgPool) -> Option<i64> {
+    match tokio::time::timeout(MIGRATION_TIMEOUT, migration_status(pool)).await {
+        Ok(Ok(status)) => status
+            .iter()
+            .filter(|migration| migration.state != MigrationState::Pending)
+            .map(|migration| migration.version)
+            .max(),
+        Ok(Err(e)) => {
+            tracing::error!("Failed to read the migration status: {:?}", e);
+            None
+        }
+        Err(_) => {
+            tracing::error!("Timed out reading the migration status");
+            None
+        }
+    }
+}
@@ -0,0 +1,176 @@
+use crate::configuration::{SecurityHeadersSettings, SecurityPolicySettings};
+use actix_web::dev::{Payload, ServiceRequest, ServiceResponse};
+use actix_web::error::ErrorInternalServerError;
+use actix_web::http::header::{self, HeaderName, HeaderValue};
+use actix_web::{FromRequest, HttpMessage, HttpRequest};
+use std::future::{ready, Future, Ready};
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// Stands for the response's nonce in a Content-Security-Policy.
+pub const NONCE_PLACEHOLDER: &str = "{nonce}";
+
+/// The headers one route group gets, checked and parsed.
+#[derive(Clone, Debug)]
+struct Policy {
+    // Still holding `{nonce}`, if it uses one
+    content_security_policy: Option<String>,
+    headers: Vec<(HeaderName, HeaderValue)>,
+}
+
+impl Policy {
+    fn parse(name: &str, settings: &SecurityPolicySettings, problems: &mut Vec<String>) -> Policy {
+        let mut headers = vec![(header::X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff"))];
+        let optional = [
+            (header::X_FRAME_OPTIONS, "frame_options", &settings.frame_options),
+            (header::REFERRER_POLICY, "referrer_policy", &settings.referrer_policy),
+            (HeaderName::from_static("permissions-policy"), "permissions_policy", &settings.permissions_policy),
+        ];
+        for (header, key, value) in optional {
+            if let Some(value) = value {
+                match HeaderValue::from_str(value) {
+                    Ok(value) => headers.push((header, value)),
+                    Err(_) => problems.push(format!("{}.{} is not a valid header value.", name, key)),
+                }
+            }
+        }
+        if let Some(csp) = &settings.content_security_policy {
+            if HeaderValue::from_str(&csp.replace(NONCE_PLACEHOLDER, &new_nonce())).is_err() {
+                problems.push(format!("{}.content_security_policy is not a valid header value.", name));
+            }
+        }
+        Policy {
+            content_security_policy: settings.content_security_policy.clone(),
+            headers,
+        }
+    }
+
+    fn uses_nonce(&self) -> bool {
+        self.content_security_policy
+            .as_deref()
+            .is_some_and(|csp| csp.contains(NONCE_PLACEHOLDER))
+    }
+}
+
+/// The security headers of every route group, and which paths are in which.
+#[derive(Clone, Debug)]
+pub struct SecurityHeaders {
+    default: Arc<Policy>,
+    groups: Arc<Vec<(Vec<String>, Policy)>>,
+}
+
+impl SecurityHeaders {
+    /// Parse `settings`, reporting every entry that is invalid.
+    pub fn from_settings(settings: &SecurityHeadersSettings) -> Result<SecurityHeaders, Vec<String>> {
+        let mut problems = Vec::new();
+        let default = Policy::parse("security_headers.default", &settings.default, &mut problems);
+        let mut groups = Vec::new();
+        for group in &settings.groups {
+            let name = format!("security_headers.groups[{}]", group.name);
+            for prefix in &group.path_prefixes {
+                if !prefix.starts_with('/') {
+                    problems.push(format!("{}.path_prefixes: `{}` does not start with `/`.", name, prefix));
+                }
+            }
+            let policy = Policy::parse(&name, &group.policy, &mut problems);
+            groups.push((group.path_prefixes.clone(), policy));
+        }
+        if problems.is_empty() {
+            Ok(SecurityHeaders {
+                default: Arc::new(default),
+                groups: Arc::new(groups),
+            })
+        } else {
+            Err(problems)
+        }
+    }
+
+    fn policy_for(&self, path: &str) -> &Policy {
+        self.groups
+            .iter()
+            .find(|(prefixes, _)| prefixes.iter().any(|prefix| in_prefix(path, prefix)))
+            .map_or(&self.default, |(_, policy)| policy)
+    }
+
+    /// The headers the response to `request` gets. If its policy uses a
+    /// nonce, a fresh one is made and left for the handler as [`CspNonce`].
+    pub fn for_request(&self, request: &ServiceRequest) -> Vec<(HeaderName, HeaderValue)> {
+        let policy = self.policy_for(request.path());
+        let mut headers = policy.headers.clone();
+        if let Some(csp) = &policy.content_security_policy {
+            let csp = if policy.uses_nonce() {
+                let nonce = new_nonce();
+                let csp = csp.replace(NONCE_PLACEHOLDER, &nonce);
+                request.extensions_mut().insert(CspNonce(nonce));
+                csp
+            } else {
+                csp.clone()
+            };
+            // Checked by `from_settings`, and nonces are alphanumeric
+            if let Ok(csp) = HeaderValue::from_str(&csp) {
+                headers.push((header::CONTENT_SECURITY_POLICY, csp));
+            }
+        }
+        headers
+    }
+}
+
+// `/archive` covers `/archive` and `/archive/...`, but not `/archives`
+fn in_prefix(path: &str, prefix: &str) -> bool {
+    match path.strip_prefix(prefix) {
+        Some(rest) => rest.is_empty() || rest.starts_with('/') || prefix.ends_with('/'),
+        None => false,
+    }
+}
+
+fn new_nonce() -> String {
+    Uuid::new_v4().to_simple().to_string()
+}
+
+/// The nonce for inline `<script>` and `<style>` elements to carry, in a
+/// route group whose Content-Security-Policy uses one. Extracting it
+/// anywhere else is an error; use `Option<CspNonce>` where that is fine.
+#[derive(Clone, Debug)]
+pub struct CspNonce(String);
+
+impl CspNonce {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::fmt::Display for CspNonce {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl FromRequest for CspNonce {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(request: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(
+            request
+                .extensions()
+                .get::<CspNonce>()
+                .cloned()
+                .ok_or_else(|| ErrorInternalServerError("The Content-Security-Policy of this route has no nonce")),
+        )
+    }
+}
+
+/// Add `headers` to the response, leaving alone the ones the handler set.
+pub async fn with_security_headers<B>(
+    headers: Vec<(HeaderName, HeaderValue)>,
+    response: impl Future<Output = Result<ServiceResponse<B>, actix_web::Error>>,
+) -> Result<ServiceResponse<B>, actix_web::Error> {
+    let mut response = response.await?;
+    let response_headers = response.headers_mut();
+    for (name, value) in headers {
+        if !response_headers.contains_key(&name) {
+            response_headers.insert(name, value);
+        }
+    }
+    Ok(response)
+}
@@ -0,0 +1,136 @@
+use actix_web::dev::ServiceResponse;
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::watch;
+
+/// Tells every part of the process that it is shutting down. Clones share
+/// the same state, so triggering one triggers them all.
+#[derive(Clone, Debug)]
+pub struct Shutdown {
+    sender: Arc<watch::Sender<bool>>,
+    receiver: watch::Receiver<bool>,
+    // HTTP requests being handled, to report the ones cut off
+    requests_in_flight: Arc<AtomicUsize>,
+    // HTTP requests dropped unanswered once shutting down
+    requests_cut_off: Arc<AtomicUsize>,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        let (sender, receiver) = watch::channel(false);
+        Self {
+            sender: Arc::new(sender),
+            receiver,
+            requests_in_flight: Arc::new(AtomicUsize::new(0)),
+            requests_cut_off: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    pub fn trigger(&self) {
+        // Only fails without receivers, and we hold one
+        let _ = self.sender.send(true);
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        *self.receiver.borrow()
+    }
+
+    /// Count a request as in flight until the returned guard is dropped.
+    /// One dropped before [`InFlightRequest::finish`] once shutting down,
+    /// as actix does when the grace period runs out, is counted as cut off.
+    pub fn request_started(&self) -> InFlightRequest {
+        self.requests_in_flight.fetch_add(1, Ordering::SeqCst);
+        InFlightRequest {
+            shutdown: self.clone(),
+            finished: false,
+        }
+    }
+
+    /// Requests being handled right now.
+    pub fn requests_in_flight(&self) -> usize {
+        self.requests_in_flight.load(Ordering::SeqCst)
+    }
+
+    /// Requests that started but did not finish: the ones dropped
+    /// unanswered since the shutdown was triggered, and the ones still in
+    /// flight. Once the server has stopped, the grace period cut these off.
+    pub fn requests_abandoned(&self) -> usize {
+        self.requests_cut_off.load(Ordering::SeqCst) + self.requests_in_flight()
+    }
+
+    /// Resolve once the shutdown has been triggered.
+    pub async fn triggered(&self) {
+        let mut receiver = self.receiver.clone();
+        while !*receiver.borrow() {
+            if receiver.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A request counted by [`Shutdown::requests_in_flight`] until dropped.
+#[must_use]
+#[derive(Debug)]
+pub struct InFlightRequest {
+    shutdown: Shutdown,
+    finished: bool,
+}
+
+impl InFlightRequest {
+    /// The request was answered.
+    pub fn finish(mut self) {
+        self.finished = true;
+    }
+}
+
+impl Drop for InFlightRequest {
+    fn drop(&mut self) {
+        self.shutdown.requests_in_flight.fetch_sub(1, Ordering::SeqCst);
+        // Clients hanging up before then are none of our doing
+        if !self.finished && self.shutdown.is_triggered() {
+            self.shutdown.requests_cut_off.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Handle the request, counting it as in flight until it is answered.
+pub async fn with_in_flight_count<B>(
+    in_flight: InFlightRequest,
+    response: impl Future<Output = Result<ServiceResponse<B>, actix_web::Error>>,
+) -> Result<ServiceResponse<B>, actix_web::Error> {
+    let response = response.await;
+    in_flight.finish();
+    response
+}
+
+/// Trigger `shutdown` on SIGTERM, which is how platforms stop a process,
+/// or on Ctrl-C.
+pub async fn shutdown_on_signal(shutdown: Shutdown) {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Failed to listen for Ctrl-C.");
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to listen for SIGTERM.")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        _ = ctrl_c => tracing::info!("Received Ctrl-C, shutting down."),
+        _ = terminate => tracing::info!("Received SIGTERM, shutting down."),
+    }
+    shutdown.trigger();
+}
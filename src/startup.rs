@@ -1,45 +1,407 @@
-use crate::routes::{health_check, subscribe};
+use crate::configuration::{DatabaseSettings, EmailEventsSettings, Environment, RetrySettings, Settings};
+use crate::routes::{
+    archive, archived_issue, atom_feed, get_log_level, handle_email_event, health_check, liveness, readiness, rss_feed, preview_newsletter, publish_newsletter, set_log_level, subscribe, track_click, track_open, unsubscribe, version,
+};
+use crate::templating::NewsletterLayout;
 use super::{controller};
-use actix_web::{web, App, HttpServer, http};
+use actix_web::{web, App, HttpServer};
 use actix_web::dev::Server;
-use actix_cors::Cors;
+use crate::cors::CorsPolicy;
+use crate::migrations::run_migrations;
+use crate::monitoring::{metrics, prometheus, record_request, track_queries, with_query_totals};
+use crate::rate_limit::{prune_periodically, RateLimitByIp, RateLimiter};
+use crate::request_id::{assign_request_id, problem_details, with_request_id};
+use crate::security_headers::{with_security_headers, SecurityHeaders};
+use crate::shutdown::{with_in_flight_count, Shutdown};
+use actix_web::dev::Service;
+use crate::telemetry::PropagatingRootSpanBuilder;
+use crate::tls::{hsts_header, reload_on_change, run_redirect, server_config, with_hsts, ReloadingCertificate, ServerTls};
 use tracing_actix_web::TracingLogger;
+use sqlx::postgres::PgPoolOptions;
 use sqlx::PgPool;
+use std::future::Future;
 use std::net::TcpListener;
+use std::sync::Arc;
+use std::time::Duration;
 
+/// The HTTP API, bound and wired up from the settings but not yet serving.
+pub struct Application {
+    port: u16,
+    server: Server,
+    // Serves `/metrics` when it has a port of its own
+    metrics_server: Option<(u16, Server)>,
+    // Redirects plain HTTP to HTTPS, when serving HTTPS
+    redirect_server: Option<(u16, Server)>,
+    db_pool: PgPool,
+    startup_retry: RetrySettings,
+    migrate_on_startup: bool,
+    shutdown: Shutdown,
+}
 
+impl Application {
+    pub async fn build(configuration: Settings) -> Result<Self, std::io::Error> {
+        let connection_pool = get_connection_pool(&configuration.database);
+        Self::build_with_pool(configuration, connection_pool).await
+    }
+
+    /// Like [`build`](Self::build), on a pool shared with something else,
+    /// such as the delivery worker.
+    pub async fn build_with_pool(configuration: Settings, connection_pool: PgPool) -> Result<Self, std::io::Error> {
+        let newsletter_layout = configuration
+            .newsletter
+            .layout()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        let address = format!(
+            "{}:{}",
+            configuration.application.host, configuration.application.port
+        );
+        let listener = TcpListener::bind(address)?;
+        // Port 0 lets the OS pick one, so ask the listener what we got
+        let port = listener.local_addr()?.port();
+        let cors = CorsPolicy::from_settings(&configuration.cors)
+            .map_err(|problems| std::io::Error::new(std::io::ErrorKind::InvalidInput, problems.join(" ")))?;
+        let security_headers = SecurityHeaders::from_settings(&configuration.security_headers)
+            .map_err(|problems| std::io::Error::new(std::io::ErrorKind::InvalidInput, problems.join(" ")))?;
+        let rate_limiter = RateLimiter::from_settings(&configuration.rate_limit, connection_pool.clone())
+            .map_err(|problems| std::io::Error::new(std::io::ErrorKind::InvalidInput, problems.join(" ")))?;
+        // Install the recorder before anything is measured
+        prometheus();
+        let metrics_server = match configuration.application.metrics_port {
+            Some(metrics_port) => {
+                let listener = TcpListener::bind(format!("{}:{}", configuration.application.host, metrics_port))?;
+                let metrics_port = listener.local_addr()?.port();
+                Some((metrics_port, run_metrics(listener, connection_pool.clone())?))
+            }
+            None => None,
+        };
+        let shutdown = Shutdown::new();
+        tokio::spawn(prune_periodically(rate_limiter.clone(), shutdown.clone()));
+        let mut redirect_server = None;
+        let tls = match &configuration.application.tls {
+            Some(settings) => {
+                let certificate = Arc::new(ReloadingCertificate::load(settings)?);
+                tokio::spawn(reload_on_change(
+                    certificate.clone(),
+                    Duration::from_secs(settings.reload_interval_seconds),
+                    shutdown.clone(),
+                ));
+                if let Some(redirect_port) = settings.redirect_port {
+                    let listener = TcpListener::bind(format!("{}:{}", configuration.application.host, redirect_port))?;
+                    let redirect_port = listener.local_addr()?.port();
+                    redirect_server = Some((redirect_port, run_redirect(listener, port)?));
+                }
+                Some(ServerTls {
+                    config: server_config(certificate),
+                    hsts: settings.hsts_max_age_seconds.map(hsts_header),
+                })
+            }
+            None => None,
+        };
+        let server = run(
+            listener,
+            connection_pool.clone(),
+            newsletter_layout,
+            configuration.application.base_url,
+            configuration.newsletter.title,
+            configuration.email_events,
+            cors,
+            security_headers,
+            rate_limiter,
+            shutdown.clone(),
+            Duration::from_secs(configuration.application.shutdown_grace_period_seconds),
+            metrics_server.is_none(),
+            configuration.environment,
+            tls,
+        )?;
+        Ok(Self {
+            port,
+            server,
+            metrics_server,
+            redirect_server,
+            db_pool: connection_pool,
+            startup_retry: configuration.database.startup_retry,
+            migrate_on_startup: configuration.database.migrate_on_startup,
+            shutdown,
+        })
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// The port serving `/metrics`, if not the main one.
+    pub fn metrics_port(&self) -> Option<u16> {
+        self.metrics_server.as_ref().map(|(port, _)| *port)
+    }
+
+    /// The port redirecting plain HTTP to HTTPS, if any.
+    pub fn redirect_port(&self) -> Option<u16> {
+        self.redirect_server.as_ref().map(|(port, _)| *port)
+    }
+
+    /// Triggering it stops the server gracefully.
+    pub fn shutdown(&self) -> Shutdown {
+        self.shutdown.clone()
+    }
+
+    /// Serve requests while waiting for the database to come up (and
+    /// migrating it, if asked to), and stop with an error if that fails.
+    ///
+    /// Once shut down, new connections are refused and in-flight requests
+    /// get the grace period to finish before the pool is closed.
+    pub async fn run_until_stopped(self) -> Result<(), std::io::Error> {
+        self.run_until_stopped_alongside(std::future::ready(())).await
+    }
+
+    /// Like [`run_until_stopped`](Self::run_until_stopped), with `task`
+    /// running next to the server, such as a delivery worker sharing its
+    /// pool. The shutdown is triggered once the server stops, and the pool
+    /// is only closed once `task` is done too.
+    pub async fn run_until_stopped_alongside(
+        self,
+        task: impl Future<Output = ()>,
+    ) -> Result<(), std::io::Error> {
+        let Application {
+            server,
+            metrics_server,
+            redirect_server,
+            db_pool,
+            startup_retry,
+            migrate_on_startup,
+            shutdown,
+            ..
+        } = self;
+        let handle = server.handle();
+        let metrics_handle = metrics_server.map(|(_, metrics_server)| {
+            let metrics_handle = metrics_server.handle();
+            tokio::spawn(metrics_server);
+            metrics_handle
+        });
+        let redirect_handle = redirect_server.map(|(_, redirect_server)| {
+            let redirect_handle = redirect_server.handle();
+            tokio::spawn(redirect_server);
+            redirect_handle
+        });
+        let stop_on_shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            stop_on_shutdown.triggered().await;
+            tracing::info!("Stopping the HTTP server.");
+            handle.stop(true).await;
+        });
+        let pool = db_pool.clone();
+        let prepare_database = async move {
+            wait_for_database(&pool, &startup_retry)
+                .await
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::NotConnected, e))?;
+            if migrate_on_startup {
+                run_migrations(&pool)
+                    .await
+                    .map_err(std::io::Error::other)?;
+            }
+            Ok::<(), std::io::Error>(())
+        };
+        let serve = async {
+            let outcome = tokio::select! {
+                outcome = server => outcome,
+                Err(e) = prepare_database => Err(e),
+            };
+            // Nothing running alongside should outlive a server that failed
+            shutdown.trigger();
+            if let Some(metrics_handle) = metrics_handle {
+                metrics_handle.stop(true).await;
+            }
+            if let Some(redirect_handle) = redirect_handle {
+                redirect_handle.stop(true).await;
+            }
+            let abandoned = shutdown.requests_abandoned();
+            if abandoned > 0 {
+                tracing::warn!(abandoned, "The grace period ran out before every request was answered.");
+            }
+            tracing::info!("The HTTP server has stopped.");
+            outcome
+        };
+        let (outcome, ()) = tokio::join!(serve, task);
+        db_pool.close().await;
+        outcome
+    }
+}
+
+/// A server for `/metrics` alone, to keep it off the public port.
+pub fn run_metrics(listener: TcpListener, db_pool: PgPool) -> Result<Server, std::io::Error> {
+    let db_pool = web::Data::new(db_pool);
+    let server = HttpServer::new(move || {
+        App::new()
+            .route("/metrics", web::get().to(metrics))
+            .app_data(db_pool.clone())
+    })
+    .disable_signals()
+    .workers(1)
+    .listen(listener)?
+    .run();
+    Ok(server)
+}
+
+// Connections are only opened once a query needs one, so the
+// application can start while the database is still coming up
+pub fn get_connection_pool(configuration: &DatabaseSettings) -> PgPool {
+    let mut options = PgPoolOptions::new()
+        .max_connections(configuration.max_connections)
+        .min_connections(configuration.min_connections)
+        // This is how long `acquire` waits, in this version of sqlx
+        .connect_timeout(Duration::from_millis(configuration.acquire_timeout_milliseconds))
+        .idle_timeout(configuration.idle_timeout_seconds.map(Duration::from_secs))
+        .max_lifetime(configuration.max_lifetime_seconds.map(Duration::from_secs));
+    if configuration.min_connections > configuration.max_connections {
+        // Rejected by `Settings::validate`; don't let sqlx panic on it regardless
+        options = options.min_connections(configuration.max_connections);
+    }
+    options.connect_lazy_with(configuration.with_db())
+}
+
+/// Try to connect until it works, backing off exponentially between
+/// attempts, and give up after `retry.max_attempts`.
+#[tracing::instrument(name = "Waiting for the database", skip_all)]
+pub async fn wait_for_database(pool: &PgPool, retry: &RetrySettings) -> Result<(), sqlx::Error> {
+    let mut backoff = Duration::from_millis(retry.initial_backoff_milliseconds);
+    let mut attempt = 1;
+    loop {
+        match pool.acquire().await {
+            Ok(_) => {
+                tracing::info!(attempt, "Connected to the database");
+                return Ok(());
+            }
+            Err(e) if attempt >= retry.max_attempts => {
+                tracing::error!(attempt, error.cause_chain = ?e, "Giving up on the database");
+                return Err(e);
+            }
+            Err(e) => {
+                tracing::warn!(
+                    attempt,
+                    backoff_milliseconds = backoff.as_millis() as u64,
+                    error.cause_chain = ?e,
+                    "Failed to connect to the database, retrying"
+                );
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(Duration::from_millis(retry.max_backoff_milliseconds));
+                attempt += 1;
+            }
+        }
+    }
+}
+
+// Wrapped in a newtype so it can be told apart from other `String`s in the application state
+pub struct ApplicationBaseUrl(pub String);
+
+// Shown in the public archive and feeds
+pub struct NewsletterTitle(pub String);
 
 // Notice the different signature!
 // We return `Server` on the happy path and we dropped the `async` keyword
+#[allow(clippy::too_many_arguments)]
 pub fn run(
     listener: TcpListener,
 	// New parameter!
-    db_pool: PgPool
+    db_pool: PgPool,
+    newsletter_layout: NewsletterLayout,
+    base_url: String,
+    newsletter_title: String,
+    email_events: EmailEventsSettings,
+    cors: CorsPolicy,
+    security_headers: SecurityHeaders,
+    rate_limiter: RateLimiter,
+    shutdown: Shutdown,
+    shutdown_grace_period: Duration,
+    // Off when `/metrics` is served on a port of its own
+    serve_metrics: bool,
+    environment: Environment,
+    // Serve HTTPS rather than plain HTTP
+    tls: Option<ServerTls>,
 ) -> Result<Server, std::io::Error> {
     // Wrap the connection in a smart pointer
     let db_pool = web::Data::new(db_pool);
+    let newsletter_layout = web::Data::new(newsletter_layout);
+    let base_url = web::Data::new(ApplicationBaseUrl(base_url));
+    let newsletter_title = web::Data::new(NewsletterTitle(newsletter_title));
+    let email_events = web::Data::new(email_events);
+    let shutdown_data = web::Data::new(shutdown.clone());
+    let environment = web::Data::new(environment);
+    let rate_limiter_data = web::Data::new(rate_limiter.clone());
+    let hsts = tls.as_ref().and_then(|tls| tls.hsts.clone());
     // transfer ownership of the AppState to the HttpServer via the `move`.
     let server = HttpServer::new(move || {
-        let cors = Cors::default()
-            .allowed_origin("https://xenodochial-ardinghelli-436772.netlify.app")
-            .allowed_origin("http://127.0.0.1:3000")
-            .allowed_origin("http://localhost:3000")
-            .send_wildcard()
-            .allowed_methods(vec!["GET", "POST", "PUT", "DELETE"])
-            .allowed_headers(vec![http::header::AUTHORIZATION, http::header::ACCEPT])
-            .allowed_header(http::header::CONTENT_TYPE)
-            .max_age(3600);
+        let shutdown = shutdown.clone();
+        let hsts = hsts.clone();
+        let security_headers = security_headers.clone();
         App::new()
-            .wrap(TracingLogger::default())
-            .wrap(cors)
+            .wrap(TracingLogger::<PropagatingRootSpanBuilder>::new())
+            // Outside the logger, for its root span to report the totals
+            .wrap_fn(|request, service| {
+                let totals = track_queries(&request);
+                with_query_totals(totals, service.call(request))
+            })
+            .wrap(cors.middleware())
             .configure(controller::init_user_controller)
             .route("/health_check", web::get().to(health_check))
+            .route("/health/live", web::get().to(liveness))
+            .route("/health/ready", web::get().to(readiness))
+            .route("/version", web::get().to(version))
             // A new entry in our routing table for POST /subscriptions requests
             .route("/subscriptions", web::post().to(subscribe))
+            .route("/subscriptions/unsubscribe/{token}", web::get().to(unsubscribe))
+            .route("/newsletters", web::post().to(publish_newsletter))
+            .route("/newsletters/preview", web::post().to(preview_newsletter))
+            .configure(controller::init_newsletter_issue_controller)
+            .route("/t/o/{token}", web::get().to(track_open))
+            .route("/t/c/{token}", web::get().to(track_click))
+            .route("/webhooks/email-events", web::post().to(handle_email_event))
+            .route("/archive", web::get().to(archive))
+            .route("/archive/{slug}", web::get().to(archived_issue))
+            .route("/feed.xml", web::get().to(atom_feed))
+            .route("/feed.rss", web::get().to(rss_feed))
+            .route("/admin/log-level", web::get().to(get_log_level))
+            .route("/admin/log-level", web::put().to(set_log_level))
+            .configure(|cfg| {
+                if serve_metrics {
+                    cfg.route("/metrics", web::get().to(metrics));
+                }
+            })
             // Register the connection as part of the application state
             .app_data(db_pool.clone())
+            .app_data(newsletter_layout.clone())
+            .app_data(base_url.clone())
+            .app_data(newsletter_title.clone())
+            .app_data(email_events.clone())
+            .app_data(shutdown_data.clone())
+            .app_data(environment.clone())
+            .app_data(rate_limiter_data.clone())
+            // Inside the metrics, so the requests turned away are counted
+            .wrap(RateLimitByIp(rate_limiter.clone()))
+            .wrap_fn(|request, service| {
+                let method = request.method().clone();
+                record_request(method, service.call(request))
+            })
+            .wrap_fn(|request, service| problem_details(service.call(request)))
+            // Outside the problem details, so errors get the headers too
+            .wrap_fn(move |request, service| {
+                let headers = security_headers.for_request(&request);
+                with_security_headers(headers, service.call(request))
+            })
+            .wrap_fn(|request, service| {
+                let request_id = assign_request_id(&request);
+                with_request_id(request_id, service.call(request))
+            })
+            .wrap_fn(move |request, service| with_hsts(hsts.clone(), service.call(request)))
+            // Outermost, so every request is counted
+            .wrap_fn(move |request, service| {
+                with_in_flight_count(shutdown.request_started(), service.call(request))
+            })
     })
-    .listen(listener)?
-    .run();
-    Ok(server)
+    // `Shutdown` decides when to stop, not actix's own signal handlers
+    .disable_signals()
+    .shutdown_timeout(shutdown_grace_period.as_secs());
+    let server = match tls {
+        Some(tls) => server.listen_rustls(listener, tls.config)?,
+        None => server.listen(listener)?,
+    };
+    Ok(server.run())
 }
\ No newline at end of file
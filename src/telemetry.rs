@@ -1,8 +1,33 @@
+use crate::build_info::{GIT_COMMIT, VERSION};
+use crate::configuration::{OtlpProtocol, TelemetrySettings};
+use crate::monitoring::QueryTotals;
+use crate::request_id::RequestId;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::HttpMessage;
+use chrono::{DateTime, Utc};
+use opentelemetry::propagation::{Extractor, Injector};
+use opentelemetry::sdk::propagation::TraceContextPropagator;
+use opentelemetry::sdk::trace::{self, Tracer, TracerProvider};
+use opentelemetry::sdk::Resource;
+use opentelemetry::trace::{TraceContextExt, TraceError};
+use opentelemetry::{global, KeyValue};
+use opentelemetry_otlp::{SpanExporterBuilder, WithExportConfig};
+use secrecy::{ExposeSecret, Secret};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
 use tracing::subscriber::set_global_default;
-use tracing::Subscriber;
+use tracing::{Span, Subscriber};
+use tracing_actix_web::{root_span, DefaultRootSpanBuilder, RootSpanBuilder};
 use tracing_bunyan_formatter::{BunyanFormattingLayer, JsonStorageLayer};
 use tracing_log::LogTracer;
-use tracing_subscriber::{layer::SubscriberExt, EnvFilter, Registry};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::filter::ParseError;
+use tracing_subscriber::{layer::SubscriberExt, reload, EnvFilter, Registry};
 use tracing_subscriber::fmt::MakeWriter;
 
 /// Compose multiple layers into a `tracing`'s subscriber.
@@ -15,11 +40,14 @@ use tracing_subscriber::fmt::MakeWriter;
 /// We need to explicitly call out that the returned subscriber is 
 /// `Send` and `Sync` to make it possible to pass it to `init_subscriber`
 /// later on.
+/// The [`LogLevel`] returned along changes the subscriber's filter.
 pub fn get_subscriber<Sink>(
     name: String, 
     env_filter: String,
+    redactor: Redactor,
+    tracer: Tracer,
     sink: Sink,
-) -> impl Subscriber + Sync + Send
+) -> (impl Subscriber + Sync + Send, LogLevel)
     where
     // This "weird" syntax is a higher-ranked trait bound (HRTB)
     // It basically means that Sink implements the `MakeWriter`
@@ -31,21 +59,438 @@ pub fn get_subscriber<Sink>(
     // We are falling back to printing all spans at info-level or above 
     // if the RUST_LOG environment variable has not been set.
     let env_filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(env_filter));
-    let formatting_layer = BunyanFormattingLayer::new(name, sink);
+    let default_filter = env_filter.to_string();
+    // Swappable while the subscriber is in use
+    let (env_filter, handle) = reload::Layer::new(env_filter);
+    // Every line names the build that emitted it
+    let build_fields = HashMap::from([
+        ("version".to_string(), serde_json::Value::from(VERSION)),
+        ("git_commit".to_string(), serde_json::Value::from(GIT_COMMIT)),
+    ]);
+    // Scrub every record on its way out, whichever layer produced it
+    let formatting_layer = BunyanFormattingLayer::with_default_fields(name, redactor.wrap(sink), build_fields);
     // The `with` method is provided by `SubscriberExt`, an extension
     // trait for `Subscriber` exposed by `tracing_subscriber`
-    Registry::default()
+    let subscriber = Registry::default()
         .with(env_filter)
         .with(JsonStorageLayer)
         .with(formatting_layer)
+        // Spans go to OpenTelemetry too, which exports them if configured
+        .with(tracing_opentelemetry::layer().with_tracer(tracer));
+    (subscriber, LogLevel::new(handle, default_filter))
 }
-/// Register a subscriber as global default to process span data.
+/// Register a subscriber as global default to process span data, and its
+/// [`LogLevel`] as the one [`log_level`] returns.
 ///
 /// It should only be called once!
-pub fn init_subscriber(subscriber: impl Subscriber + Send + Sync) {
+pub fn init_subscriber(subscriber: impl Subscriber + Send + Sync, log_level: LogLevel) {
     // Redirect all `log`'s events to our subscriber
     LogTracer::init().expect("Failed to set logger");
     // `set_global_default` can be used by applications to specify 
     // what subscriber should be used to process spans.  
     set_global_default(subscriber).expect("Failed to set subscriber");
-}
\ No newline at end of file
+    // Trace context travels in W3C `traceparent` and `tracestate` headers
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    let _ = LOG_LEVEL.set(log_level);
+}
+
+static LOG_LEVEL: OnceLock<LogLevel> = OnceLock::new();
+
+/// The filter of the global subscriber, once `init_subscriber` set it.
+pub fn log_level() -> Option<&'static LogLevel> {
+    LOG_LEVEL.get()
+}
+
+/// A subscriber's filter, which can be changed while the process runs:
+/// `info,sqlx=trace` for a few minutes during an incident, say.
+#[derive(Clone)]
+pub struct LogLevel {
+    handle: reload::Handle<EnvFilter, Registry>,
+    // The filter at startup, which changes revert to
+    default: String,
+    // Bumped on every change, so a pending revert knows it was superseded
+    generation: Arc<AtomicU64>,
+    reverts_at: Arc<Mutex<Option<DateTime<Utc>>>>,
+}
+
+impl LogLevel {
+    fn new(handle: reload::Handle<EnvFilter, Registry>, default: String) -> Self {
+        LogLevel {
+            handle,
+            default,
+            generation: Arc::new(AtomicU64::new(0)),
+            reverts_at: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// The filter in use, as `RUST_LOG` directives.
+    pub fn current(&self) -> String {
+        self.handle
+            .with_current(|filter| filter.to_string())
+            .unwrap_or_default()
+    }
+
+    pub fn default_filter(&self) -> &str {
+        &self.default
+    }
+
+    /// When the filter goes back to the default, if a change is set to.
+    pub fn reverts_at(&self) -> Option<DateTime<Utc>> {
+        *self.reverts_at.lock().unwrap()
+    }
+
+    /// Filter with `directives` from now on, or only for `revert_after`.
+    /// Must be called from within a Tokio runtime if `revert_after` is set.
+    pub fn set(&self, directives: &str, revert_after: Option<Duration>) -> Result<(), ParseError> {
+        let filter = EnvFilter::try_new(directives)?;
+        let generation = self.replace(filter);
+        if let Some(revert_after) = revert_after {
+            *self.reverts_at.lock().unwrap() = chrono::Duration::from_std(revert_after)
+                .ok()
+                .and_then(|revert_after| Utc::now().checked_add_signed(revert_after));
+            let log_level = self.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(revert_after).await;
+                if log_level.generation.load(Ordering::SeqCst) == generation {
+                    tracing::info!(filter = %log_level.default, "Reverting the log filter");
+                    log_level.reset();
+                }
+            });
+        }
+        Ok(())
+    }
+
+    /// Go back to the filter at startup.
+    pub fn reset(&self) {
+        self.replace(EnvFilter::new(&self.default));
+    }
+
+    // Returns the generation of the new filter
+    fn replace(&self, filter: EnvFilter) -> u64 {
+        let generation = self.generation.fetch_add(1, Ordering::SeqCst) + 1;
+        *self.reverts_at.lock().unwrap() = None;
+        if let Err(e) = self.handle.reload(filter) {
+            // Only if the subscriber is gone, and with it anything to filter
+            tracing::error!("Failed to reload the log filter: {:?}", e);
+        }
+        generation
+    }
+}
+
+/// The OpenTelemetry tracer provider. Spans are batched and exported over
+/// OTLP if an endpoint is configured; otherwise they only carry the trace
+/// context along, to the logs and to the services we call.
+///
+/// The provider has to outlive every tracer made from it: hand it to
+/// `opentelemetry::global::set_tracer_provider` and call
+/// `opentelemetry::global::shutdown_tracer_provider` on exit, which flushes
+/// the spans not exported yet.
+pub fn get_tracer_provider(settings: &TelemetrySettings) -> Result<TracerProvider, TraceError> {
+    let resource = Resource::new([KeyValue::new("service.name", settings.service_name.clone())]);
+    let mut builder = TracerProvider::builder().with_config(trace::config().with_resource(resource));
+    if let Some(endpoint) = &settings.otlp_endpoint {
+        let exporter: SpanExporterBuilder = match settings.otlp_protocol {
+            OtlpProtocol::Grpc => opentelemetry_otlp::new_exporter().tonic().with_endpoint(endpoint).into(),
+            // Unlike gRPC, each signal has a path of its own
+            OtlpProtocol::Http => opentelemetry_otlp::new_exporter()
+                .http()
+                .with_endpoint(format!("{}/v1/traces", endpoint.trim_end_matches('/')))
+                .into(),
+        };
+        let exporter = exporter.build_span_exporter()?;
+        builder = builder.with_batch_exporter(exporter, opentelemetry::runtime::Tokio);
+    }
+    Ok(builder.build())
+}
+
+/// Roots each request's span in the trace of the caller, if its
+/// `traceparent` header names one, and logs the trace id along, with the
+/// [`RequestId`] the response is sent with. Once the request is handled,
+/// the span gets the number of queries it made and the time they took.
+pub struct PropagatingRootSpanBuilder;
+
+impl RootSpanBuilder for PropagatingRootSpanBuilder {
+    fn on_request_start(request: &ServiceRequest) -> Span {
+        let span = root_span!(
+            request,
+            db.queries = tracing::field::Empty,
+            db.duration_us = tracing::field::Empty
+        );
+        let parent = global::get_text_map_propagator(|propagator| {
+            propagator.extract(&RequestHeaders(request.headers()))
+        });
+        span.set_parent(parent);
+        let trace_id = span.context().span().span_context().trace_id();
+        span.record("trace_id", &tracing::field::display(trace_id));
+        // In place of the id `TracingLogger` generated, which callers never see
+        if let Some(request_id) = request.extensions().get::<RequestId>() {
+            span.record("request_id", &tracing::field::display(request_id));
+        }
+        span
+    }
+
+    fn on_request_end<B>(span: Span, outcome: &Result<ServiceResponse<B>, actix_web::Error>) {
+        let totals = outcome
+            .as_ref()
+            .ok()
+            .and_then(|response| response.request().extensions().get::<Arc<QueryTotals>>().cloned());
+        if let Some(totals) = totals {
+            span.record("db.queries", &totals.count());
+            span.record("db.duration_us", &(totals.duration().as_micros() as u64));
+        }
+        DefaultRootSpanBuilder::on_request_end(span, outcome)
+    }
+}
+
+struct RequestHeaders<'a>(&'a actix_web::http::header::HeaderMap);
+
+impl Extractor for RequestHeaders<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|name| name.as_str()).collect()
+    }
+}
+
+/// Headers carrying the current span's trace context, for the requests we
+/// make to other services to join our trace.
+pub fn trace_context_headers() -> reqwest::header::HeaderMap {
+    let mut headers = OutgoingHeaders(reqwest::header::HeaderMap::new());
+    let context = Span::current().context();
+    global::get_text_map_propagator(|propagator| propagator.inject_context(&context, &mut headers));
+    headers.0
+}
+
+struct OutgoingHeaders(reqwest::header::HeaderMap);
+
+impl Injector for OutgoingHeaders {
+    fn set(&mut self, key: &str, value: String) {
+        let name = reqwest::header::HeaderName::from_bytes(key.as_bytes());
+        let value = reqwest::header::HeaderValue::from_str(&value);
+        if let (Ok(name), Ok(value)) = (name, value) {
+            self.0.insert(name, value);
+        }
+    }
+}
+/// How personal data, such as email addresses and names, shows up in logs
+/// and traces when wrapped in [`Redacted`].
+#[derive(serde::Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PiiPolicy {
+    // As is, for local development
+    Full,
+    // A digest: the same value can be followed across records, not read
+    Hashed,
+    // `u***@gmail.com`
+    #[default]
+    Masked,
+}
+
+impl PiiPolicy {
+    pub fn apply(self, value: &str) -> String {
+        match self {
+            PiiPolicy::Full => value.to_string(),
+            PiiPolicy::Hashed => {
+                // Addresses differing only in case are the same mailbox
+                let digest = Sha256::digest(value.trim().to_lowercase().as_bytes());
+                format!("sha256:{}", &hex::encode(digest)[..16])
+            }
+            PiiPolicy::Masked => match value.rsplit_once('@') {
+                Some((local, domain)) => format!("{}@{}", mask(local), domain),
+                None => mask(value),
+            },
+        }
+    }
+}
+
+fn mask(value: &str) -> String {
+    match value.chars().next() {
+        Some(first) => format!("{}***", first),
+        None => "***".to_string(),
+    }
+}
+
+static PII_POLICY: OnceLock<PiiPolicy> = OnceLock::new();
+
+/// Set how [`Redacted`] values are rendered. Only the first call counts;
+/// until it is made, they are masked.
+pub fn set_pii_policy(policy: PiiPolicy) {
+    let _ = PII_POLICY.set(policy);
+}
+
+fn pii_policy() -> PiiPolicy {
+    PII_POLICY.get().copied().unwrap_or_default()
+}
+
+/// Personal data on its way into a span or an event, rendered as the
+/// [`PiiPolicy`] says: `fields(user_email = %Redacted(&form.email))`.
+pub struct Redacted<T>(pub T);
+
+impl<T: fmt::Display> fmt::Display for Redacted<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&pii_policy().apply(&self.0.to_string()))
+    }
+}
+
+impl<T: fmt::Display> fmt::Debug for Redacted<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+// Log fields whose values are never written out, matched as a
+// case-insensitive substring of the field name
+const SENSITIVE_FIELDS: [&str; 7] = [
+    "password",
+    "secret",
+    "token",
+    "authorization",
+    "session_key",
+    "api_key",
+    "cookie",
+];
+const REDACTED: &str = "[REDACTED]";
+
+/// Strips known-sensitive fields and the configured secret values from
+/// log records before they reach the sink.
+#[derive(Clone, Default)]
+pub struct Redactor {
+    secrets: Arc<Vec<Secret<String>>>,
+}
+
+impl Redactor {
+    pub fn new(secrets: Vec<Secret<String>>) -> Self {
+        // An empty secret would match everywhere
+        let secrets = secrets
+            .into_iter()
+            .filter(|secret| !secret.expose_secret().is_empty())
+            .collect();
+        Self {
+            secrets: Arc::new(secrets),
+        }
+    }
+
+    pub fn wrap<Sink>(self, sink: Sink) -> RedactingMakeWriter<Sink> {
+        RedactingMakeWriter {
+            inner: sink,
+            redactor: self,
+        }
+    }
+
+    // Records are JSON lines; anything else is only scrubbed of secret values
+    fn redact(&self, line: &[u8]) -> Vec<u8> {
+        match serde_json::from_slice::<serde_json::Value>(line) {
+            Ok(mut record) => {
+                if self.redact_value(&mut record) {
+                    let mut redacted = serde_json::to_vec(&record).expect("A JSON value always serializes");
+                    redacted.push(b'\n');
+                    redacted
+                } else {
+                    line.to_vec()
+                }
+            }
+            Err(_) => {
+                let line = String::from_utf8_lossy(line);
+                self.redact_str(&line).unwrap_or_else(|| line.into_owned()).into_bytes()
+            }
+        }
+    }
+
+    // Returns whether anything was redacted
+    fn redact_value(&self, value: &mut serde_json::Value) -> bool {
+        match value {
+            serde_json::Value::Object(fields) => {
+                let mut redacted = false;
+                for (name, value) in fields.iter_mut() {
+                    let name = name.to_lowercase();
+                    if SENSITIVE_FIELDS.iter().any(|field| name.contains(field)) {
+                        *value = serde_json::Value::String(REDACTED.into());
+                        redacted = true;
+                    } else {
+                        redacted |= self.redact_value(value);
+                    }
+                }
+                redacted
+            }
+            serde_json::Value::Array(values) => {
+                let mut redacted = false;
+                for value in values {
+                    redacted |= self.redact_value(value);
+                }
+                redacted
+            }
+            serde_json::Value::String(text) => match self.redact_str(text) {
+                Some(redacted) => {
+                    *text = redacted;
+                    true
+                }
+                None => false,
+            },
+            _ => false,
+        }
+    }
+
+    fn redact_str(&self, text: &str) -> Option<String> {
+        let mut redacted = None;
+        for secret in self.secrets.iter() {
+            let current: &str = redacted.as_deref().unwrap_or(text);
+            if current.contains(secret.expose_secret().as_str()) {
+                redacted = Some(current.replace(secret.expose_secret().as_str(), REDACTED));
+            }
+        }
+        redacted
+    }
+}
+
+/// A [`MakeWriter`] whose writers pass every line through a [`Redactor`].
+pub struct RedactingMakeWriter<Sink> {
+    inner: Sink,
+    redactor: Redactor,
+}
+
+impl<'a, Sink: MakeWriter<'a>> MakeWriter<'a> for RedactingMakeWriter<Sink> {
+    type Writer = RedactingWriter<Sink::Writer>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        RedactingWriter {
+            inner: self.inner.make_writer(),
+            redactor: self.redactor.clone(),
+            line: Vec::new(),
+        }
+    }
+}
+
+/// Buffers what is written until a full line can be redacted.
+pub struct RedactingWriter<W: Write> {
+    inner: W,
+    redactor: Redactor,
+    line: Vec<u8>,
+}
+
+impl<W: Write> Write for RedactingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.line.extend_from_slice(buf);
+        while let Some(end) = self.line.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.line.drain(..=end).collect();
+            self.inner.write_all(&self.redactor.redact(&line))?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        if !self.line.is_empty() {
+            let line = std::mem::take(&mut self.line);
+            self.inner.write_all(&self.redactor.redact(&line))?;
+        }
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Drop for RedactingWriter<W> {
+    fn drop(&mut self) {
+        // Nowhere to report a failure to
+        let _ = self.flush();
+    }
+}
@@ -0,0 +1,277 @@
+use pulldown_cmark::{html, Event, Options, Parser, Tag};
+use std::borrow::Cow;
+use std::path::Path;
+use uuid::Uuid;
+
+/// Variables that are substituted per subscriber at delivery time.
+pub const SUBSCRIBER_VARIABLES: [&str; 2] = ["name", "unsubscribe_url"];
+/// Variables that are only available to layout templates.
+const LAYOUT_VARIABLES: [&str; 2] = ["title", "content"];
+
+#[derive(Debug)]
+pub struct TemplateError(String);
+
+impl std::fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+#[derive(Clone, Debug)]
+enum Segment {
+    Literal(String),
+    Variable(&'static str),
+}
+
+/// A template with `{{ variable }}` placeholders, parsed up front so that
+/// rendering it for every subscriber never fails.
+#[derive(Clone, Debug)]
+pub struct Template {
+    segments: Vec<Segment>,
+}
+
+impl Template {
+    /// Parse `source`, rejecting any placeholder that is not in `allowed`.
+    pub fn parse(source: &str, allowed: &[&'static str]) -> Result<Template, TemplateError> {
+        let mut segments = Vec::new();
+        let mut rest = source;
+        while let Some(start) = rest.find("{{") {
+            let end = rest[start..].find("}}").ok_or_else(|| {
+                TemplateError("A `{{` placeholder is never closed with `}}`.".into())
+            })?;
+            let name = rest[start + 2..start + end].trim();
+            let variable = allowed.iter().find(|v| **v == name).ok_or_else(|| {
+                TemplateError(format!(
+                    "`{{{{ {} }}}}` is not a known template variable. Use one of: {}.",
+                    name,
+                    allowed.join(", ")
+                ))
+            })?;
+            if start > 0 {
+                segments.push(Segment::Literal(rest[..start].to_string()));
+            }
+            segments.push(Segment::Variable(variable));
+            rest = &rest[start + end + 2..];
+        }
+        if !rest.is_empty() {
+            segments.push(Segment::Literal(rest.to_string()));
+        }
+        Ok(Template { segments })
+    }
+
+    /// Load a template from disk, e.g. one of the layouts in `templates/`.
+    pub fn from_file(path: impl AsRef<Path>, allowed: &[&'static str]) -> Result<Template, TemplateError> {
+        let path = path.as_ref();
+        let source = std::fs::read_to_string(path).map_err(|e| {
+            TemplateError(format!("Failed to read template {}: {}", path.display(), e))
+        })?;
+        Template::parse(&source, allowed)
+    }
+
+    fn render(&self, lookup: impl Fn(&str) -> Cow<'static, str>) -> String {
+        self.segments
+            .iter()
+            .map(|segment| match segment {
+                Segment::Literal(literal) => Cow::Borrowed(literal.as_str()),
+                Segment::Variable(name) => lookup(name),
+            })
+            .collect()
+    }
+
+    /// Replace the layout-only variables, leaving subscriber variables in place.
+    fn compose(&self, title: &str, content: &Template) -> Template {
+        let mut segments = Vec::new();
+        for segment in &self.segments {
+            match segment {
+                Segment::Variable("content") => segments.extend(content.segments.iter().cloned()),
+                Segment::Variable("title") => segments.push(Segment::Literal(title.to_string())),
+                other => segments.push(other.clone()),
+            }
+        }
+        Template { segments }
+    }
+}
+
+/// The HTML and plain text layouts every issue is wrapped in.
+#[derive(Clone, Debug)]
+pub struct NewsletterLayout {
+    pub html: Template,
+    pub text: Template,
+}
+
+impl NewsletterLayout {
+    pub fn from_files(
+        html_path: impl AsRef<Path>,
+        text_path: impl AsRef<Path>,
+    ) -> Result<NewsletterLayout, TemplateError> {
+        let allowed = [&LAYOUT_VARIABLES[..], &SUBSCRIBER_VARIABLES[..]].concat();
+        Ok(NewsletterLayout {
+            html: Template::from_file(html_path, &allowed)?,
+            text: Template::from_file(text_path, &allowed)?,
+        })
+    }
+
+    /// A layout that adds nothing around the content, for pages that
+    /// provide their own chrome (e.g. the public archive).
+    pub fn bare() -> NewsletterLayout {
+        let content = Template::parse("{{ content }}", &LAYOUT_VARIABLES)
+            .expect("`content` is a layout variable");
+        NewsletterLayout {
+            html: content.clone(),
+            text: content,
+        }
+    }
+}
+
+/// The values substituted into an issue for a single subscriber.
+pub struct Personalization<'a> {
+    pub name: &'a str,
+    pub unsubscribe_url: &'a str,
+}
+
+impl Personalization<'_> {
+    fn get(&self, variable: &str) -> &str {
+        match variable {
+            "name" => self.name,
+            "unsubscribe_url" => self.unsubscribe_url,
+            other => unreachable!("`{}` is not a subscriber variable", other),
+        }
+    }
+}
+
+#[derive(serde::Serialize, Debug)]
+pub struct RenderedNewsletter {
+    pub html: String,
+    pub text: String,
+}
+
+/// An issue rendered from Markdown and wrapped in the layout, still
+/// waiting for the per-subscriber variables.
+#[derive(Clone, Debug)]
+pub struct NewsletterTemplate {
+    html: Template,
+    text: Template,
+}
+
+impl NewsletterTemplate {
+    pub fn compose(
+        title: &str,
+        markdown: &str,
+        layout: &NewsletterLayout,
+    ) -> Result<NewsletterTemplate, TemplateError> {
+        let source = Template::parse(markdown, &SUBSCRIBER_VARIABLES)?;
+        // Markdown would mangle or percent-encode `{{ }}` (e.g. inside a link
+        // destination), so placeholders travel through the renderer as
+        // alphanumeric sentinels and are turned back into variables afterwards.
+        let nonce = Uuid::new_v4().to_simple().to_string();
+        let sentinels: Vec<(String, &'static str)> = SUBSCRIBER_VARIABLES
+            .iter()
+            .enumerate()
+            .map(|(i, name)| (format!("tpl{}{}z", nonce, i), *name))
+            .collect();
+        let markdown: String = source
+            .segments
+            .iter()
+            .map(|segment| match segment {
+                Segment::Literal(literal) => literal.as_str(),
+                Segment::Variable(name) => {
+                    &sentinels.iter().find(|(_, v)| v == name).unwrap().0
+                }
+            })
+            .collect();
+
+        let mut html_content = String::new();
+        html::push_html(&mut html_content, Parser::new_ext(&markdown, Options::empty()));
+        let text_content = markdown_to_text(&markdown);
+
+        Ok(NewsletterTemplate {
+            html: layout
+                .html
+                .compose(&escape_html(title), &from_sentinels(&html_content, &sentinels)),
+            text: layout
+                .text
+                .compose(title, &from_sentinels(&text_content, &sentinels)),
+        })
+    }
+
+    /// Substitute the subscriber's values, escaping them in the HTML part
+    /// so that e.g. a `name` cannot inject markup into the email.
+    pub fn render(&self, personalization: &Personalization) -> RenderedNewsletter {
+        RenderedNewsletter {
+            html: self
+                .html
+                .render(|v| Cow::Owned(escape_html(personalization.get(v)))),
+            text: self
+                .text
+                .render(|v| Cow::Owned(personalization.get(v).to_string())),
+        }
+    }
+}
+
+fn from_sentinels(rendered: &str, sentinels: &[(String, &'static str)]) -> Template {
+    let mut segments = Vec::new();
+    let mut rest = rendered;
+    while let Some((start, sentinel, name)) = sentinels
+        .iter()
+        .filter_map(|(sentinel, name)| rest.find(sentinel.as_str()).map(|i| (i, sentinel, *name)))
+        .min_by_key(|(i, _, _)| *i)
+    {
+        if start > 0 {
+            segments.push(Segment::Literal(rest[..start].to_string()));
+        }
+        segments.push(Segment::Variable(name));
+        rest = &rest[start + sentinel.len()..];
+    }
+    if !rest.is_empty() {
+        segments.push(Segment::Literal(rest.to_string()));
+    }
+    Template { segments }
+}
+
+/// A plain text rendition of the Markdown: formatting is dropped,
+/// links are spelled out and raw HTML is skipped.
+fn markdown_to_text(markdown: &str) -> String {
+    let mut text = String::new();
+    let mut links = Vec::new();
+    for event in Parser::new_ext(markdown, Options::empty()) {
+        match event {
+            Event::Text(t) | Event::Code(t) => text.push_str(&t),
+            Event::SoftBreak | Event::HardBreak => text.push('\n'),
+            Event::Rule => text.push_str("----\n\n"),
+            Event::Start(Tag::Item) => text.push_str("- "),
+            Event::Start(Tag::Link(_, destination, _)) => links.push(destination),
+            Event::End(Tag::Link(..)) => {
+                if let Some(destination) = links.pop() {
+                    text.push_str(&format!(" ({})", destination));
+                }
+            }
+            Event::End(Tag::Item) => text.push('\n'),
+            Event::End(Tag::List(_)) => text.push('\n'),
+            Event::End(Tag::Paragraph)
+            | Event::End(Tag::Heading(..))
+            | Event::End(Tag::BlockQuote)
+            | Event::End(Tag::CodeBlock(_)) => text.push_str("\n\n"),
+            _ => {}
+        }
+    }
+    let mut text = text.trim_end().to_string();
+    text.push('\n');
+    text
+}
+
+pub fn escape_html(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#x27;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
@@ -0,0 +1,172 @@
+use crate::configuration::TlsSettings;
+use crate::shutdown::Shutdown;
+use actix_web::dev::{Server, ServiceResponse};
+use actix_web::http::header;
+use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::{any_supported_type, CertifiedKey};
+use rustls::{Certificate, PrivateKey, ServerConfig};
+use std::fs::File;
+use std::future::Future;
+use std::io::BufReader;
+use std::net::TcpListener;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+/// Read a certificate chain, leaf first, and its private key from PEM files.
+pub fn load_certified_key(cert_path: &str, key_path: &str) -> Result<CertifiedKey, std::io::Error> {
+    let invalid = |message: String| std::io::Error::new(std::io::ErrorKind::InvalidData, message);
+    let certificates = rustls_pemfile::certs(&mut BufReader::new(File::open(cert_path)?))?;
+    if certificates.is_empty() {
+        return Err(invalid(format!("{} holds no certificate", cert_path)));
+    }
+    let key = rustls_pemfile::read_all(&mut BufReader::new(File::open(key_path)?))?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| invalid(format!("{} holds no private key", key_path)))?;
+    let key = any_supported_type(&key).map_err(|e| invalid(format!("{}: {}", key_path, e)))?;
+    Ok(CertifiedKey::new(
+        certificates.into_iter().map(Certificate).collect(),
+        key,
+    ))
+}
+
+/// Hands every handshake the certificate last read from disk, so a renewed
+/// one is served without a restart.
+pub struct ReloadingCertificate {
+    cert_path: String,
+    key_path: String,
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl ReloadingCertificate {
+    pub fn load(settings: &TlsSettings) -> Result<Self, std::io::Error> {
+        let certified_key = load_certified_key(&settings.cert_path, &settings.key_path)?;
+        Ok(Self {
+            cert_path: settings.cert_path.clone(),
+            key_path: settings.key_path.clone(),
+            current: RwLock::new(Arc::new(certified_key)),
+        })
+    }
+
+    /// Read the files again. On failure the certificate in use is kept, as
+    /// a half-written renewal is better retried than served.
+    pub fn reload(&self) -> Result<(), std::io::Error> {
+        let certified_key = load_certified_key(&self.cert_path, &self.key_path)?;
+        *self.current.write().unwrap() = Arc::new(certified_key);
+        Ok(())
+    }
+
+    fn modified_at(&self) -> Option<(SystemTime, SystemTime)> {
+        let modified_at = |path: &str| std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok();
+        Some((modified_at(&self.cert_path)?, modified_at(&self.key_path)?))
+    }
+}
+
+impl ResolvesServerCert for ReloadingCertificate {
+    fn resolve(&self, _: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+/// A rustls config serving `certificate`. actix adds `h2` and `http/1.1`
+/// to its ALPN protocols, which is how clients get HTTP/2.
+pub fn server_config(certificate: Arc<ReloadingCertificate>) -> ServerConfig {
+    ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_cert_resolver(certificate)
+}
+
+/// What `startup::run` needs to serve HTTPS.
+pub struct ServerTls {
+    pub config: ServerConfig,
+    // `Strict-Transport-Security`, if it is to be sent
+    pub hsts: Option<header::HeaderValue>,
+}
+
+/// Reload `certificate` whenever its files change, checking every
+/// `interval`, until the shutdown is triggered.
+pub async fn reload_on_change(certificate: Arc<ReloadingCertificate>, interval: Duration, shutdown: Shutdown) {
+    let mut last_modified = certificate.modified_at();
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(interval) => {}
+            _ = shutdown.triggered() => return,
+        }
+        let modified = certificate.modified_at();
+        if modified.is_none() || modified == last_modified {
+            continue;
+        }
+        match certificate.reload() {
+            Ok(()) => {
+                tracing::info!(cert_path = %certificate.cert_path, "Reloaded the TLS certificate");
+                last_modified = modified;
+            }
+            Err(e) => tracing::error!(
+                cert_path = %certificate.cert_path,
+                error.cause_chain = ?e,
+                "Failed to reload the TLS certificate, keeping the one in use"
+            ),
+        }
+    }
+}
+
+/// The `Strict-Transport-Security` value for `max_age_seconds`.
+pub fn hsts_header(max_age_seconds: u64) -> header::HeaderValue {
+    header::HeaderValue::from_str(&format!("max-age={}; includeSubDomains", max_age_seconds))
+        .expect("A number makes a valid header value")
+}
+
+/// Add `Strict-Transport-Security` to the response, if there is one to add.
+pub async fn with_hsts<B>(
+    hsts: Option<header::HeaderValue>,
+    response: impl Future<Output = Result<ServiceResponse<B>, actix_web::Error>>,
+) -> Result<ServiceResponse<B>, actix_web::Error> {
+    let mut response = response.await?;
+    if let Some(hsts) = hsts {
+        response.headers_mut().insert(header::STRICT_TRANSPORT_SECURITY, hsts);
+    }
+    Ok(response)
+}
+
+struct HttpsPort(u16);
+
+/// A server answering every plain HTTP request with a permanent redirect
+/// to the same URL over HTTPS on `https_port`.
+pub fn run_redirect(listener: TcpListener, https_port: u16) -> Result<Server, std::io::Error> {
+    let https_port = web::Data::new(HttpsPort(https_port));
+    let server = HttpServer::new(move || {
+        App::new()
+            .app_data(https_port.clone())
+            .default_service(web::to(redirect_to_https))
+    })
+    .disable_signals()
+    .workers(1)
+    .listen(listener)?
+    .run();
+    Ok(server)
+}
+
+async fn redirect_to_https(request: HttpRequest, https_port: web::Data<HttpsPort>) -> HttpResponse {
+    let connection_info = request.connection_info();
+    let host = connection_info.host();
+    // Drop the plain HTTP port, bracketed IPv6 addresses included
+    let host = match host.rsplit_once(':') {
+        Some((name, port)) if !name.ends_with(':') && port.chars().all(|c| c.is_ascii_digit()) => name,
+        _ => host,
+    };
+    let authority = match https_port.0 {
+        443 => host.to_string(),
+        port => format!("{}:{}", host, port),
+    };
+    let path = request.uri().path_and_query().map_or("/", |path| path.as_str());
+    HttpResponse::PermanentRedirect()
+        .insert_header((header::LOCATION, format!("https://{}{}", authority, path)))
+        .finish()
+}
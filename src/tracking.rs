@@ -0,0 +1,79 @@
+use crate::startup::ApplicationBaseUrl;
+use crate::templating::escape_html;
+use uuid::Uuid;
+
+/// A link in an outgoing email that was rewritten to go through
+/// `GET /t/c/{token}`.
+pub struct TrackedLink {
+    pub token: String,
+    pub url: String,
+}
+
+pub struct TrackedHtml {
+    pub html: String,
+    pub links: Vec<TrackedLink>,
+}
+
+/// Tokens end up in URLs handed to subscribers, so they must not be guessable.
+pub fn new_token() -> String {
+    Uuid::new_v4().to_simple().to_string()
+}
+
+/// Point every `http(s)` link in `html` at the click tracker and embed
+/// the open tracking pixel for `delivery_token`.
+///
+/// `untracked_url` is left alone: unsubscribing should not depend on
+/// the tracker being up.
+pub fn track_html(
+    html: &str,
+    base_url: &ApplicationBaseUrl,
+    delivery_token: &str,
+    untracked_url: &str,
+) -> TrackedHtml {
+    let mut tracked = String::with_capacity(html.len());
+    let mut links = Vec::new();
+    let mut rest = html;
+    while let Some(i) = rest.find("href=\"") {
+        let start = i + "href=\"".len();
+        tracked.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let end = match rest.find('"') {
+            Some(end) => end,
+            None => break,
+        };
+        let url = unescape_html(&rest[..end]);
+        if (url.starts_with("http://") || url.starts_with("https://")) && url != untracked_url {
+            let token = new_token();
+            tracked.push_str(&escape_html(&format!("{}/t/c/{}", base_url.0, token)));
+            links.push(TrackedLink { token, url });
+        } else {
+            tracked.push_str(&rest[..end]);
+        }
+        rest = &rest[end..];
+    }
+    tracked.push_str(rest);
+
+    let pixel = format!(
+        r#"<img src="{}" width="1" height="1" alt="">"#,
+        escape_html(&format!("{}/t/o/{}", base_url.0, delivery_token))
+    );
+    match tracked.rfind("</body>") {
+        Some(i) => tracked.insert_str(i, &pixel),
+        None => tracked.push_str(&pixel),
+    }
+    TrackedHtml {
+        html: tracked,
+        links,
+    }
+}
+
+// Reverses `escape_html`, plus the entities pulldown-cmark uses in link destinations
+fn unescape_html(value: &str) -> String {
+    value
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#x27;", "'")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
+}